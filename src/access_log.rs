@@ -0,0 +1,471 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Lines waiting for the writer, new ones are dropped when it falls behind.
+const QUEUE_SIZE: usize = 8192;
+/// How often dropped lines are reported.
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub enum AccessLogFormat {
+    Json,
+    /// Apache/Nginx combined log format.
+    Combined,
+    /// Text with `{field}` placeholders, e.g. `{client_ip} {method} {path} {status}`.
+    Template(String),
+}
+
+impl AccessLogFormat {
+    /// Anything but `json` and `combined` has to contain a placeholder, so typos are not logged literally.
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "json" => Ok(Self::Json),
+            "combined" => Ok(Self::Combined),
+            template if template.contains('{') => Ok(Self::Template(template.to_string())),
+            other => Err(format!("Unknown access log format {other:?}, expected json, combined or template with {{field}} placeholders")),
+        }
+    }
+}
+
+/// Escapes quotes, backslashes and bytes outside printable ASCII as `\xHH` like nginx,
+/// so client supplied values can't forge fields or lines.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        match b {
+            b'"' | b'\\' | ..0x20 | 0x7f.. => escaped.push_str(&format!("\\x{b:02X}")),
+            _ => escaped.push(b as char),
+        }
+    }
+    escaped
+}
+
+/// Finished http request.
+pub struct AccessLogEntry {
+    pub time: DateTime<Utc>,
+    pub request_id: String,
+    pub client_ip: String,
+    /// Authenticated user, empty for anonymous requests.
+    pub user: String,
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    /// Matched record, empty if none matched.
+    pub rule: String,
+    pub upstream: String,
+    pub status: u16,
+    /// Response body bytes sent to the client.
+    pub bytes: usize,
+    /// From starting upstream connection to receiving response header.
+    pub upstream_latency: Option<Duration>,
+    pub latency: Duration,
+    pub referer: String,
+    pub user_agent: String,
+    /// Time the connection stayed upgraded, `None` for regular requests.
+    pub upgrade_duration: Option<Duration>,
+    /// Bytes sent to upstream over upgraded connection.
+    pub upgrade_bytes_sent: u64,
+    /// Bytes received from upstream over upgraded connection.
+    pub upgrade_bytes_received: u64,
+}
+
+impl AccessLogEntry {
+    /// Field value for templates, `None` for unknown names.
+    fn field(&self, name: &str) -> Option<String> {
+        let or_dash = |v: &str| if v.is_empty() { "-".to_string() } else { v.to_string() };
+        Some(match name {
+            "time" => self.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "request_id" => self.request_id.clone(),
+            "client_ip" => or_dash(&self.client_ip),
+            "user" => or_dash(&self.user),
+            "host" => or_dash(&self.host),
+            "method" => self.method.clone(),
+            "path" => self.path.clone(),
+            "protocol" => self.protocol.clone(),
+            "rule" => or_dash(&self.rule),
+            "upstream" => or_dash(&self.upstream),
+            "status" => self.status.to_string(),
+            "bytes" => self.bytes.to_string(),
+            "upstream_latency" => self.upstream_latency.map_or("-".to_string(), |l| format!("{:.3}", l.as_secs_f64())),
+            "latency" => format!("{:.3}", self.latency.as_secs_f64()),
+            "referer" => or_dash(&self.referer),
+            "user_agent" => or_dash(&self.user_agent),
+            "upgrade_duration" => self.upgrade_duration.map_or("-".to_string(), |d| format!("{:.3}", d.as_secs_f64())),
+            "upgrade_bytes_sent" => self.upgrade_bytes_sent.to_string(),
+            "upgrade_bytes_received" => self.upgrade_bytes_received.to_string(),
+            _ => return None,
+        })
+    }
+
+    fn json(&self) -> String {
+        serde_json::json!({
+            "time": self.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "request_id": self.request_id,
+            "client_ip": self.client_ip,
+            "user": self.user,
+            "host": self.host,
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "rule": self.rule,
+            "upstream": self.upstream,
+            "status": self.status,
+            "bytes": self.bytes,
+            "upstream_latency": self.upstream_latency.map(|l| l.as_secs_f64()),
+            "latency": self.latency.as_secs_f64(),
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "upgrade_duration": self.upgrade_duration.map(|d| d.as_secs_f64()),
+            "upgrade_bytes_sent": self.upgrade_bytes_sent,
+            "upgrade_bytes_received": self.upgrade_bytes_received,
+        })
+        .to_string()
+    }
+
+    fn combined(&self) -> String {
+        let or_dash = |v: &str| if v.is_empty() { "-".to_string() } else { escape(v) };
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            or_dash(&self.client_ip),
+            or_dash(&self.user),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method),
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            self.bytes,
+            or_dash(&self.referer),
+            or_dash(&self.user_agent),
+        )
+    }
+
+    /// Replaces known `{field}` placeholders with escaped values, everything else is kept as is.
+    fn template(&self, template: &str) -> String {
+        let mut line = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            line.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find('}').and_then(|end| Some((end, escape(&self.field(&rest[1..end])?))));
+            match value {
+                Some((end, value)) => {
+                    line.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    line.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        line.push_str(rest);
+        line
+    }
+}
+
+/// Handle used by gateways, lines are written by [`AccessLogWriter`].
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: mpsc::Sender<String>,
+    /// Lines dropped since the writer last reported them.
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let line = match &self.format {
+            AccessLogFormat::Json => entry.json(),
+            AccessLogFormat::Combined => entry.combined(),
+            AccessLogFormat::Template(template) => entry.template(template),
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub enum AccessLogOutput {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Log file renamed to `<path>.<timestamp>` once it grows over `max_size`
+/// or gets older than `interval`. Only `keep` newest rotated files are left, 0 keeps all.
+pub struct RotatingFile {
+    pub path: PathBuf,
+    pub max_size: Option<u64>,
+    pub interval: Option<Duration>,
+    pub keep: usize,
+}
+
+/// Opened log file with its size and age, used to decide when to rotate.
+struct OpenedFile {
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    async fn open(&self) -> std::io::Result<OpenedFile> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        let size = file.metadata().await?.len();
+        Ok(OpenedFile { file: BufWriter::new(file), size, opened: Instant::now() })
+    }
+
+    fn is_due(&self, opened: &OpenedFile) -> bool {
+        self.max_size.is_some_and(|max| opened.size >= max)
+            || self.interval.is_some_and(|interval| opened.opened.elapsed() >= interval)
+    }
+
+    async fn rotate(&self) -> std::io::Result<()> {
+        let suffix = Utc::now().format("%Y%m%dT%H%M%S%.3f");
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{suffix}"));
+        tokio::fs::rename(&self.path, &rotated).await?;
+        if self.keep == 0 {
+            return Ok(());
+        }
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut old = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                old.push(entry.path());
+            }
+        }
+        // timestamp suffix sorts in creation order
+        old.sort();
+        for path in old.iter().take(old.len().saturating_sub(self.keep)) {
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes access log lines in the background, so requests never wait for disk.
+pub struct AccessLogWriter {
+    rx: Mutex<Option<mpsc::Receiver<String>>>,
+    output: AccessLogOutput,
+    dropped: Arc<AtomicU64>,
+}
+
+pub fn access_log(format: AccessLogFormat, output: AccessLogOutput) -> (AccessLog, AccessLogWriter) {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let dropped = Arc::new(AtomicU64::new(0));
+    (
+        AccessLog { format, tx, dropped: Arc::clone(&dropped) },
+        AccessLogWriter { rx: Mutex::new(Some(rx)), output, dropped },
+    )
+}
+
+/// Writes all queued lines, returns bytes written.
+async fn write_lines<W: AsyncWrite + Unpin>(out: &mut W, first: String, rx: &mut mpsc::Receiver<String>) -> std::io::Result<u64> {
+    let mut written = 0;
+    let mut next = Some(first);
+    while let Some(line) = next {
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(b"\n").await?;
+        written += line.len() as u64 + 1;
+        next = rx.try_recv().ok();
+    }
+    out.flush().await?;
+    Ok(written)
+}
+
+#[async_trait]
+impl BackgroundService for AccessLogWriter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(mut rx) = self.rx.lock().unwrap().take() else {
+            return;
+        };
+        let mut stdout = tokio::io::stdout();
+        let mut opened: Option<OpenedFile> = None;
+        let mut report = tokio::time::interval(DROP_REPORT_INTERVAL);
+        let mut closing = false;
+        loop {
+            let line = tokio::select! {
+                _ = shutdown.changed(), if !closing => {
+                    if *shutdown.borrow() {
+                        // queued lines are still written, recv returns None after them
+                        rx.close();
+                        closing = true;
+                    }
+                    continue;
+                }
+                _ = report.tick() => {
+                    let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        warn!("Access log writer is behind, dropped {dropped} line(s)");
+                    }
+                    continue;
+                }
+                line = rx.recv() => match line {
+                    Some(line) => line,
+                    None => return,
+                },
+            };
+            let file = match &self.output {
+                AccessLogOutput::Stdout => {
+                    if let Err(e) = write_lines(&mut stdout, line, &mut rx).await {
+                        warn!("Can't write access log to stdout: {e}");
+                    }
+                    continue;
+                }
+                AccessLogOutput::File(file) => file,
+            };
+            if let Some(current) = &opened
+                && file.is_due(current)
+            {
+                opened = None;
+                if let Err(e) = file.rotate().await {
+                    warn!("Can't rotate access log {}: {e}", file.path.display());
+                }
+            }
+            if opened.is_none() {
+                match file.open().await {
+                    Ok(v) => opened = Some(v),
+                    Err(e) => {
+                        warn!("Can't open access log {}: {e}, dropping line", file.path.display());
+                        continue;
+                    }
+                }
+            }
+            let Some(current) = opened.as_mut() else { continue };
+            match write_lines(&mut current.file, line, &mut rx).await {
+                Ok(written) => current.size += written,
+                Err(e) => {
+                    warn!("Can't write access log {}: {e}", file.path.display());
+                    opened = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z").unwrap().to_utc(),
+            request_id: "id".to_string(),
+            client_ip: "192.0.2.1".to_string(),
+            user: String::new(),
+            host: "example.com".to_string(),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            rule: String::new(),
+            upstream: String::new(),
+            status: 200,
+            bytes: 5,
+            upstream_latency: None,
+            latency: Duration::from_millis(1500),
+            referer: String::new(),
+            user_agent: "curl".to_string(),
+            upgrade_duration: None,
+            upgrade_bytes_sent: 0,
+            upgrade_bytes_received: 0,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("simple-proxy-log-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn escapes_quotes_controls_and_non_ascii() {
+        assert_eq!(escape(r#"a"b\c"#), r"a\x22b\x5Cc");
+        assert_eq!(escape("line\nforged\r\t\x7f"), r"line\x0Aforged\x0D\x09\x7F");
+        assert_eq!(escape("é"), r"\xC3\xA9");
+        // invalid UTF-8 of header values is replaced before formatting
+        assert_eq!(escape(&String::from_utf8_lossy(b"\xff")), r"\xEF\xBF\xBD");
+    }
+
+    #[test]
+    fn combined_escapes_client_values() {
+        let entry = AccessLogEntry {
+            path: "/\"x\" 200".to_string(),
+            user_agent: "ua\"\n1.2.3.4 - -".to_string(),
+            ..entry()
+        };
+        assert_eq!(
+            entry.combined(),
+            r#"192.0.2.1 - - [02/Jan/2026:03:04:05 +0000] "GET /\x22x\x22 200 HTTP/1.1" 200 5 "-" "ua\x22\x0A1.2.3.4 - -""#
+        );
+    }
+
+    #[test]
+    fn template_keeps_unknown_placeholders() {
+        let entry = AccessLogEntry { path: "/a\nb".to_string(), ..entry() };
+        assert_eq!(
+            entry.template("{client_ip} {unknown} {path} {status} {latency} {user} {rule"),
+            r"192.0.2.1 {unknown} /a\x0Ab 200 1.500 - {rule"
+        );
+        assert_eq!(entry.template("{{status}} $status {}"), "{200} $status {}");
+    }
+
+    #[test]
+    fn formats_are_parsed() {
+        assert!(matches!(AccessLogFormat::parse("json"), Ok(AccessLogFormat::Json)));
+        assert!(matches!(AccessLogFormat::parse("combined"), Ok(AccessLogFormat::Combined)));
+        assert!(matches!(AccessLogFormat::parse("{status}"), Ok(AccessLogFormat::Template(_))));
+        assert!(AccessLogFormat::parse("jsno").is_err());
+    }
+
+    #[tokio::test]
+    async fn rotation_keeps_newest_files() {
+        let dir = temp_dir();
+        let file = RotatingFile { path: dir.join("access.log"), max_size: Some(10), interval: None, keep: 2 };
+        let (_tx, mut rx) = mpsc::channel(1);
+        for i in 0..4 {
+            let mut opened = file.open().await.unwrap();
+            assert!(!file.is_due(&opened));
+            opened.size += write_lines(&mut opened.file, format!("line {i} over limit"), &mut rx).await.unwrap();
+            assert!(file.is_due(&opened));
+            file.rotate().await.unwrap();
+            // rotated names have millisecond timestamps
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut rotated: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        rotated.sort();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(std::fs::read_to_string(&rotated[0]).unwrap(), "line 2 over limit\n");
+        assert_eq!(std::fs::read_to_string(&rotated[1]).unwrap(), "line 3 over limit\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_lines_are_written_on_shutdown() {
+        let dir = temp_dir();
+        let path = dir.join("access.log");
+        let output = AccessLogOutput::File(RotatingFile { path: path.clone(), max_size: None, interval: None, keep: 0 });
+        let (log, writer) = access_log(AccessLogFormat::parse("{status}").unwrap(), output);
+        for status in [200, 404] {
+            log.write(&AccessLogEntry { status, ..entry() });
+        }
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        shutdown.send(true).unwrap();
+        writer.start(watch).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "200\n404\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use pingora::http::ResponseHeader;
+use pingora::lb::{
+    Backend, Backends, LoadBalancer,
+    health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck},
+    selection::{Consistent, Random, RoundRobin},
+};
+use pingora::{CustomCode, Error};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::{HealthCheckKind, HealthCheckRecord, UpstreamTls};
+use crate::discovery::{DnsDiscovery, TokioResolverOpt};
+
+/// Bound for backend search, see [`LoadBalancer::select`].
+const MAX_ITERATIONS: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LbStrategy {
+    /// Every backend in turn, weights are ignored.
+    #[default]
+    RoundRobin,
+    /// Random backend, weights are ignored.
+    Random,
+    /// Round robin proportional to backend weights.
+    Weighted,
+    /// Consistent hashing of the request key, weights are respected.
+    Ketama,
+}
+
+enum Selector {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    Ketama(LoadBalancer<Consistent>),
+}
+
+/// Everything a balancer is built from. Balancers with unchanged spec are
+/// carried over on reload, so health of their backends is not reset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BalancerSpec {
+    /// Used in logs.
+    pub name: String,
+    pub strategy: LbStrategy,
+    /// `host:port` addresses with weights, hostnames resolve to all their ips.
+    pub upstreams: Vec<(String, usize)>,
+    pub dns_refresh: Option<Duration>,
+    /// Check with host sent by http checks and tls of https upstreams.
+    pub health_check: Option<(HealthCheckRecord, String, Option<UpstreamTls>)>,
+}
+
+/// Backend selection for one record, with optional active health checks
+/// and re-resolution of hostname upstreams.
+pub struct Balancer {
+    spec: BalancerSpec,
+    selector: Selector,
+    health_interval: Option<Duration>,
+    next_check: Mutex<Instant>,
+    /// Set by [`DnsDiscovery`], `None` if there are no hostnames to re-resolve.
+    next_resolve: Option<Arc<Mutex<Instant>>>,
+    name: String,
+}
+
+/// Health check of one record, names backends by the record they belong to in logs.
+struct RecordHealthCheck {
+    name: String,
+    inner: Box<dyn HealthCheck + Send + Sync>,
+}
+
+#[async_trait]
+impl HealthCheck for RecordHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        self.inner.check(target).await
+    }
+
+    fn backend_summary(&self, target: &Backend) -> String {
+        format!("Upstream {} of {}", target.addr, self.name)
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.inner.health_threshold(success)
+    }
+}
+
+impl HealthCheckRecord {
+    /// `name` is used in logs, `host` is sent as Host header by http checks, `tls` is set for https upstreams.
+    fn build(&self, name: &str, host: &str, tls: Option<&UpstreamTls>) -> RecordHealthCheck {
+        let timeout = Duration::from_secs(self.timeout.unwrap_or(1));
+        let rise = self.rise.unwrap_or(2);
+        let fall = self.fall.unwrap_or(3);
+        let inner: Box<dyn HealthCheck + Send + Sync> = match self.kind.unwrap_or_default() {
+            HealthCheckKind::Tcp => {
+                let mut hc = TcpHealthCheck::new();
+                hc.consecutive_success = rise;
+                hc.consecutive_failure = fall;
+                hc.peer_template.options.connection_timeout = Some(timeout);
+                hc
+            }
+            HealthCheckKind::Http => {
+                let host = self.host.as_deref().unwrap_or(host);
+                let mut hc = HttpHealthCheck::new(host, tls.is_some());
+                if let Some(tls) = tls {
+                    tls.apply(&mut hc.peer_template);
+                }
+                hc.consecutive_success = rise;
+                hc.consecutive_failure = fall;
+                hc.peer_template.options.connection_timeout = Some(timeout);
+                hc.peer_template.options.read_timeout = Some(timeout);
+                // path is validated while parsing config
+                hc.req.set_uri(self.path.as_deref().unwrap_or("/").parse().unwrap_or_default());
+                let expected = self.status.unwrap_or(200);
+                hc.validator = Some(Box::new(move |resp: &ResponseHeader| {
+                    if resp.status.as_u16() == expected {
+                        return Ok(());
+                    }
+                    Error::e_explain(
+                        CustomCode("unexpected status", resp.status.as_u16()),
+                        format!("expected {expected}"),
+                    )
+                }));
+                Box::new(hc)
+            }
+        };
+        RecordHealthCheck { name: name.to_string(), inner }
+    }
+}
+
+impl Balancer {
+    /// Fails if initial resolution fails.
+    pub async fn new(resolver: TokioResolverOpt, spec: BalancerSpec) -> Result<Self, String> {
+        let BalancerSpec { name, strategy, upstreams, dns_refresh, health_check } = &spec;
+        let upstreams = match strategy {
+            LbStrategy::RoundRobin | LbStrategy::Random => upstreams.iter().map(|(u, _)| (u.clone(), 1)).collect(),
+            LbStrategy::Weighted | LbStrategy::Ketama => upstreams.clone(),
+        };
+        let discovery = DnsDiscovery::new(name, resolver, upstreams, *dns_refresh);
+        let next_resolve = discovery.next_resolve();
+        let mut backends = Backends::new(Box::new(discovery));
+        if let Some((hc, host, tls)) = health_check {
+            backends.set_health_check(Box::new(hc.build(name, host, tls.as_ref())));
+        }
+        let selector = match strategy {
+            LbStrategy::RoundRobin | LbStrategy::Weighted => Selector::RoundRobin(LoadBalancer::from_backends(backends)),
+            LbStrategy::Random => Selector::Random(LoadBalancer::from_backends(backends)),
+            LbStrategy::Ketama => Selector::Ketama(LoadBalancer::from_backends(backends)),
+        };
+        let balancer = Self {
+            health_interval: health_check.as_ref().map(|(hc, _, _)| Duration::from_secs(hc.interval.unwrap_or(5).max(1))),
+            next_check: Mutex::new(Instant::now()),
+            next_resolve,
+            name: name.clone(),
+            selector,
+            spec,
+        };
+        balancer
+            .update()
+            .await
+            .map_err(|e| e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()))?;
+        Ok(balancer)
+    }
+
+    /// Balancer of `previous` config with the same spec, or a new one.
+    pub async fn carry_over(previous: &[Arc<Balancer>], resolver: TokioResolverOpt, spec: BalancerSpec) -> Result<Arc<Self>, String> {
+        if let Some(balancer) = previous.iter().find(|b| b.spec == spec) {
+            return Ok(Arc::clone(balancer));
+        }
+        Ok(Arc::new(Self::new(resolver, spec).await?))
+    }
+
+    pub async fn update(&self) -> pingora::Result<()> {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.update().await,
+            Selector::Random(lb) => lb.update().await,
+            Selector::Ketama(lb) => lb.update().await,
+        }
+    }
+
+    /// Returns first healthy backend, `key` is only used by hashing strategies.
+    pub fn select(&self, key: &[u8]) -> Option<Backend> {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.select(key, MAX_ITERATIONS),
+            Selector::Random(lb) => lb.select(key, MAX_ITERATIONS),
+            Selector::Ketama(lb) => lb.select(key, MAX_ITERATIONS),
+        }
+    }
+
+    pub fn backends(&self) -> &Backends {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.backends(),
+            Selector::Random(lb) => lb.backends(),
+            Selector::Ketama(lb) => lb.backends(),
+        }
+    }
+
+    /// Runs health check if it is configured, its interval has passed
+    /// and previous check is finished.
+    pub async fn health_check_if_due(&self) {
+        let Some(interval) = self.health_interval else { return };
+        {
+            let mut next = self.next_check.lock().unwrap();
+            let now = Instant::now();
+            if *next > now {
+                return;
+            }
+            // pushed back again after the check, blocks overlapping runs
+            *next = now + Duration::from_secs(u32::MAX as u64);
+        }
+        self.backends().run_health_check(true).await;
+        *self.next_check.lock().unwrap() = Instant::now() + interval;
+    }
+
+    pub fn has_health_check(&self) -> bool {
+        self.health_interval.is_some()
+    }
+
+    /// Re-resolves hostname upstreams when their answers expire. On failure
+    /// previous backends are kept and resolution is retried later.
+    /// Health of backends that are still present is preserved.
+    pub async fn resolve_if_due(&self) {
+        let Some(next_resolve) = &self.next_resolve else { return };
+        {
+            let mut next = next_resolve.lock().unwrap();
+            let now = Instant::now();
+            if *next > now {
+                return;
+            }
+            // discovery sets the next time, blocks overlapping runs until then
+            *next = now + Duration::from_secs(u32::MAX as u64);
+        }
+        if let Err(e) = self.update().await {
+            warn!("Can't re-resolve upstreams of {}, keeping previous ones: {}", self.name, e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()));
+        }
+    }
+
+    pub fn has_dns_refresh(&self) -> bool {
+        self.next_resolve.is_some()
+    }
+}
+
+impl std::fmt::Debug for Balancer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let strategy = match self.selector {
+            Selector::RoundRobin(_) => "RoundRobin",
+            Selector::Random(_) => "Random",
+            Selector::Ketama(_) => "Ketama",
+        };
+        let backends = self.backends().get_backend();
+        f.debug_struct("Balancer")
+            .field("strategy", &strategy)
+            .field("backends", &backends.iter().map(|b| (b.addr.to_string(), b.weight)).collect::<Vec<_>>())
+            .field("health_interval", &self.health_interval)
+            .field("dns_refresh", &self.next_resolve.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use super::*;
+    use crate::test_util;
+
+    /// Http upstream answering every request with the current `status`.
+    async fn stub(status: Arc<AtomicU16>) -> String {
+        let (addr, _) = test_util::stub(move || {
+            let status = status.load(Ordering::Relaxed);
+            format!("HTTP/1.1 {status} Stub\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+        })
+        .await;
+        addr.to_string()
+    }
+
+    fn spec(addr: &str, rise: usize, fall: usize) -> BalancerSpec {
+        let hc = HealthCheckRecord {
+            kind: Some(HealthCheckKind::Http),
+            path: Some("/health".to_string()),
+            host: None,
+            status: Some(200),
+            interval: Some(1),
+            timeout: Some(1),
+            rise: Some(rise),
+            fall: Some(fall),
+        };
+        BalancerSpec {
+            name: "http test".to_string(),
+            strategy: LbStrategy::RoundRobin,
+            upstreams: vec![(addr.to_string(), 1)],
+            dns_refresh: None,
+            health_check: Some((hc, "test".to_string(), None)),
+        }
+    }
+
+    async fn check(balancer: &Balancer, times: usize) {
+        for _ in 0..times {
+            balancer.backends().run_health_check(false).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn health_check_rise_and_fall() {
+        let status = Arc::new(AtomicU16::new(200));
+        let addr = stub(Arc::clone(&status)).await;
+        let balancer = Balancer::new(None, spec(&addr, 2, 3)).await.unwrap();
+        check(&balancer, 1).await;
+        assert!(balancer.select(&[]).is_some());
+
+        status.store(503, Ordering::Relaxed);
+        check(&balancer, 2).await;
+        assert!(balancer.select(&[]).is_some(), "unhealthy only after fall failures");
+        check(&balancer, 1).await;
+        assert!(balancer.select(&[]).is_none());
+
+        status.store(200, Ordering::Relaxed);
+        check(&balancer, 1).await;
+        assert!(balancer.select(&[]).is_none(), "healthy only after rise successes");
+        check(&balancer, 1).await;
+        assert!(balancer.select(&[]).is_some());
+    }
+
+    #[tokio::test]
+    async fn health_is_kept_by_unchanged_spec() {
+        let status = Arc::new(AtomicU16::new(503));
+        let addr = stub(status).await;
+        let balancer = Arc::new(Balancer::new(None, spec(&addr, 1, 1)).await.unwrap());
+        check(&balancer, 1).await;
+        assert!(balancer.select(&[]).is_none());
+
+        let previous = [Arc::clone(&balancer)];
+        let same = Balancer::carry_over(&previous, None, spec(&addr, 1, 1)).await.unwrap();
+        assert!(Arc::ptr_eq(&same, &balancer));
+        assert!(same.select(&[]).is_none());
+
+        let changed = Balancer::carry_over(&previous, None, spec(&addr, 2, 1)).await.unwrap();
+        assert!(!Arc::ptr_eq(&changed, &balancer));
+    }
+}
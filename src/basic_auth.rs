@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use argon2::{Argon2, CustomizedPasswordHasher, Params, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use http::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+const DEFAULT_REALM: &str = "Restricted";
+const DEFAULT_USER_HEADER: &str = "x-forwarded-user";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthRecord {
+    /// Shown by browsers in the login prompt, default "Restricted".
+    pub realm: Option<String>,
+    /// htpasswd lines `name:hash` with bcrypt or argon2 hashes.
+    pub users: Option<Vec<String>>,
+    /// htpasswd file, read when config is loaded.
+    pub users_file: Option<PathBuf>,
+    /// Remove `Authorization` before proxying, default = false.
+    pub strip_header: Option<bool>,
+    /// Header with authenticated user sent to upstream, default `X-Forwarded-User`.
+    pub user_header: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).ok()?;
+            Some(Self::Argon2(hash.to_string()))
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p)) && hash.len() == 60 {
+            Some(Self::Bcrypt(hash.to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// Hash of a random password with the same algorithm and cost.
+    fn dummy(&self) -> Option<Self> {
+        let password = uuid::Uuid::new_v4().to_string();
+        match self {
+            Self::Bcrypt(hash) => {
+                let cost = hash.get(4..6)?.parse().ok()?;
+                Some(Self::Bcrypt(bcrypt::hash(password, cost).ok()?))
+            }
+            Self::Argon2(hash) => {
+                let hash = PasswordHash::new(hash).ok()?;
+                let params = Params::try_from(&hash).ok()?;
+                let salt = uuid::Uuid::new_v4();
+                let dummy = Argon2::default()
+                    .hash_password_customized(password.as_bytes(), salt.as_bytes(), Some(hash.algorithm.as_str()), hash.version, params)
+                    .ok()?;
+                Some(Self::Argon2(dummy.to_string()))
+            }
+        }
+    }
+
+    /// Slow by design, runs on blocking threads.
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::Argon2(hash) => Argon2::default().verify_password(password.as_bytes(), hash.as_str()).is_ok(),
+        }
+    }
+}
+
+/// Users allowed by `auth` of a record.
+pub struct BasicAuth {
+    challenge: HeaderValue,
+    users: HashMap<String, Hash>,
+    /// Verified for unknown users, so they take as long to reject as wrong passwords.
+    dummy: Hash,
+    pub strip_header: bool,
+    pub user_header: HeaderName,
+    /// Digest of the last verified credentials per user, so only new passwords are hashed.
+    verified: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("challenge", &self.challenge)
+            .field("users", &self.users.keys().collect::<Vec<_>>())
+            .field("strip_header", &self.strip_header)
+            .field("user_header", &self.user_header)
+            .finish()
+    }
+}
+
+impl BasicAuth {
+    /// Reads `users_file`, it is not watched for changes.
+    pub fn try_parse(record: BasicAuthRecord) -> Result<Self, String> {
+        let BasicAuthRecord { realm, users: lines, users_file, strip_header, user_header } = record;
+        let realm = realm.unwrap_or_else(|| DEFAULT_REALM.to_string());
+        if realm.contains(['"', '\\']) {
+            return Err(format!("Realm can't contain quotes or backslashes: {realm}"));
+        }
+        let challenge = HeaderValue::from_str(&format!("Basic realm=\"{realm}\", charset=\"UTF-8\""))
+            .map_err(|_| format!("Invalid realm: {realm}"))?;
+        let user_header = user_header.as_deref().unwrap_or(DEFAULT_USER_HEADER);
+        let user_header = user_header.parse().map_err(|_| format!("Invalid user_header: {user_header}"))?;
+
+        let mut users = HashMap::new();
+        let mut first = None;
+        let mut add = |line: &str, origin: &dyn Fn() -> String| {
+            let Some((name, hash)) = line.split_once(':') else {
+                return Err(format!("{}: expected name:hash", origin()));
+            };
+            let Some(hash) = Hash::parse(hash) else {
+                return Err(format!("{}: unsupported hash of user {name}, use bcrypt or argon2", origin()));
+            };
+            first.get_or_insert_with(|| hash.clone());
+            if users.insert(name.to_string(), hash).is_some() {
+                return Err(format!("{}: duplicate user {name}", origin()));
+            }
+            Ok(())
+        };
+        for (i, line) in lines.unwrap_or_default().iter().enumerate() {
+            add(line, &|| format!("users[{i}]"))?;
+        }
+        if let Some(path) = users_file {
+            let content = std::fs::read_to_string(&path).map_err(|e| format!("Can't read users_file {}: {e}", path.display()))?;
+            for (i, line) in content.lines().enumerate().filter(|(_, l)| !(l.trim().is_empty() || l.starts_with('#'))) {
+                add(line.trim(), &|| format!("{}:{}", path.display(), i + 1))?;
+            }
+        }
+        let Some(first) = first else {
+            return Err("Auth has no users, set users or users_file".to_string());
+        };
+        let dummy = first.dummy().unwrap_or(first);
+        Ok(Self {
+            challenge,
+            users,
+            dummy,
+            strip_header: strip_header.unwrap_or(false),
+            user_header,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// `WWW-Authenticate` value of 401 responses.
+    pub fn challenge(&self) -> &HeaderValue {
+        &self.challenge
+    }
+
+    /// User of valid `Authorization` header, `None` when credentials are missing or wrong.
+    pub async fn authenticate(self: &Arc<Self>, authorization: Option<&HeaderValue>) -> Option<String> {
+        let (name, password) = Self::credentials(authorization?)?;
+        let known = self.users.get(&name);
+        let digest: [u8; 32] = Sha256::new().chain_update(&name).chain_update(":").chain_update(&password).finalize().into();
+        if known.is_some() && self.verified.lock().unwrap().get(&name) == Some(&digest) {
+            return Some(name);
+        }
+        let hash = known.unwrap_or(&self.dummy).clone();
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .inspect_err(|e| warn!("Password verification failed: {e}"))
+            .unwrap_or(false);
+        if known.is_none() {
+            // name is client supplied
+            debug!("Basic auth for unknown user");
+            return None;
+        }
+        if !valid {
+            info!("Basic auth with wrong password for user {name}");
+            return None;
+        }
+        self.verified.lock().unwrap().insert(name.clone(), digest);
+        Some(name)
+    }
+
+    fn credentials(authorization: &HeaderValue) -> Option<(String, String)> {
+        let (scheme, encoded) = authorization.to_str().ok()?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let (name, password) = String::from_utf8(decoded).ok()?.split_once(':').map(|(n, p)| (n.to_string(), p.to_string()))?;
+        Some((name, password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::PasswordHasher;
+
+    use super::*;
+
+    fn auth(users: Vec<String>) -> Arc<BasicAuth> {
+        let record = BasicAuthRecord { realm: None, users: Some(users), users_file: None, strip_header: None, user_header: None };
+        Arc::new(BasicAuth::try_parse(record).unwrap())
+    }
+
+    fn basic(name: &str, password: &str) -> HeaderValue {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(format!("{name}:{password}"));
+        HeaderValue::from_str(&format!("Basic {encoded}")).unwrap()
+    }
+
+    fn argon2_hash(password: &str) -> String {
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, Params::new(64, 1, 1, None).unwrap());
+        argon2.hash_password_with_salt(password.as_bytes(), b"0123456789abcdef").unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn unknown_user_wrong_and_correct_password() {
+        let auth = auth(vec![
+            format!("alice:{}", bcrypt::hash("secret", 4).unwrap()),
+            format!("bob:{}", argon2_hash("hunter2")),
+        ]);
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret"))).await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(Some(&basic("bob", "hunter2"))).await.as_deref(), Some("bob"));
+        assert_eq!(auth.authenticate(Some(&basic("alice", "hunter2"))).await, None);
+        assert_eq!(auth.authenticate(Some(&basic("bob", "secret"))).await, None);
+        assert_eq!(auth.authenticate(Some(&basic("mallory", "secret"))).await, None);
+        assert_eq!(auth.authenticate(Some(&basic("mallory\n", ""))).await, None);
+        assert_eq!(auth.authenticate(None).await, None);
+        assert_eq!(auth.authenticate(Some(&HeaderValue::from_static("Bearer x"))).await, None);
+        assert_eq!(auth.authenticate(Some(&HeaderValue::from_static("Basic not-base64"))).await, None);
+        assert_eq!(auth.authenticate(Some(&HeaderValue::from_static("Basic YWxpY2U="))).await, None, "no colon");
+    }
+
+    #[test]
+    fn dummy_hash_has_same_algorithm_and_cost() {
+        let Some(Hash::Bcrypt(dummy)) = Hash::Bcrypt(bcrypt::hash("secret", 5).unwrap()).dummy() else { panic!("bcrypt expected") };
+        assert!(dummy.starts_with("$2b$05$"), "{dummy}");
+
+        let hash = argon2_hash("secret");
+        let Some(Hash::Argon2(dummy)) = Hash::Argon2(hash.clone()).dummy() else { panic!("argon2 expected") };
+        let params = |hash: &str| hash.split('$').take(4).collect::<Vec<_>>().join("$");
+        assert_eq!(params(&dummy), params(&hash));
+        assert_ne!(dummy, hash);
+    }
+
+    #[tokio::test]
+    async fn verified_credentials_are_cached_by_digest() {
+        let auth = auth(vec![format!("alice:{}", bcrypt::hash("secret", 4).unwrap())]);
+        assert!(auth.verified.lock().unwrap().is_empty());
+        assert_eq!(auth.authenticate(Some(&basic("alice", "wrong"))).await, None);
+        assert_eq!(auth.authenticate(Some(&basic("mallory", "secret"))).await, None);
+        assert!(auth.verified.lock().unwrap().is_empty(), "only valid credentials are cached");
+
+        assert!(auth.authenticate(Some(&basic("alice", "secret"))).await.is_some());
+        let cached = *auth.verified.lock().unwrap().get("alice").unwrap();
+        assert!(auth.authenticate(Some(&basic("alice", "secret"))).await.is_some());
+        // cached digest doesn't let other passwords through
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret2"))).await, None);
+        assert_eq!(auth.verified.lock().unwrap().get("alice"), Some(&cached));
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        let parse = |users: Vec<&str>| {
+            let users = Some(users.into_iter().map(String::from).collect());
+            BasicAuth::try_parse(BasicAuthRecord { realm: None, users, users_file: None, strip_header: None, user_header: None })
+        };
+        assert!(parse(vec![]).unwrap_err().contains("no users"));
+        assert!(parse(vec!["alice"]).unwrap_err().contains("expected name:hash"));
+        assert!(parse(vec!["alice:{SHA}abc"]).unwrap_err().contains("unsupported hash"));
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(parse(vec![&format!("alice:{hash}"), &format!("alice:{hash}")]).unwrap_err().contains("duplicate user"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use pingora::protocols::tls::CaType;
+use pingora::utils::tls::{CertKey, WrappedX509, parse_x509};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tracing::{info, warn};
+
+const CERT_FILE: &str = "fullchain.pem";
+const KEY_FILE: &str = "privkey.pem";
+
+#[derive(Default, Debug)]
+struct Certs {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// Keyed by parent domain, `*.example.com` is stored as `example.com`.
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl Certs {
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.default.is_none()
+    }
+
+    /// Exact domain first, then wildcard of its parent, then default.
+    fn select(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(sni) = sni.map(|s| s.to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+        if let Some(cert) = self.exact.get(&sni) {
+            return Some(Arc::clone(cert));
+        }
+        // wildcard covers exactly one label
+        if let Some((_, parent)) = sni.split_once('.')
+            && let Some(cert) = self.wildcard.get(parent)
+        {
+            return Some(Arc::clone(cert));
+        }
+        self.default.clone()
+    }
+}
+
+/// Certificates picked by SNI at handshake time.
+/// `fullchain.pem` and `privkey.pem` in the root of the directory are the default certificate,
+/// ones in `<domain>/` subdirectories are used for that domain. Wildcard certificates
+/// are put in `*.example.com/` (or `_.example.com/` where `*` is not allowed in file names).
+#[derive(Debug)]
+pub struct CertResolver {
+    path: PathBuf,
+    certs: ArcSwap<Certs>,
+    /// Held while files are read or replaced, so reload never pairs a new key with old certificate.
+    files: Mutex<()>,
+}
+
+impl CertResolver {
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let certs = load_certs(&path);
+        Self { path, certs: ArcSwap::from_pointee(certs), files: Mutex::new(()) }
+    }
+
+    /// Re-reads certificates, renewed files are picked up by new handshakes.
+    pub fn reload(&self) {
+        let certs = {
+            let _files = self.files.lock().unwrap();
+            load_certs(&self.path)
+        };
+        if certs.is_empty() {
+            warn!("No certificates found in {}, keeping previous ones", self.path.display());
+            return;
+        }
+        self.certs.store(Arc::new(certs));
+    }
+
+    /// Runs `write` of certificate files without reload reading them halfway.
+    pub fn replace_files<T>(&self, write: impl FnOnce() -> T) -> T {
+        let _files = self.files.lock().unwrap();
+        write()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certs.load().is_empty()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certs.load().select(client_hello.server_name())
+    }
+}
+
+fn load_certs(path: &Path) -> Certs {
+    let mut certs = Certs::default();
+    match load_pair(path) {
+        Ok(cert) => certs.default = cert,
+        Err(e) => warn!("Can't load default certificate from {}: {e:#}", path.display()),
+    }
+    let entries = match std::fs::read_dir(path) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Can't read cert dir {}: {e}", path.display());
+            return certs;
+        }
+    };
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_ascii_lowercase();
+        let cert = match load_pair(&dir) {
+            Ok(Some(cert)) => cert,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Can't load certificate from {}: {e:#}, skipping", dir.display());
+                continue;
+            }
+        };
+        info!("Loaded certificate for {name}");
+        match name.strip_prefix("*.").or_else(|| name.strip_prefix("_.")) {
+            Some(parent) => certs.wildcard.insert(parent.to_string(), cert),
+            None => certs.exact.insert(name, cert),
+        };
+    }
+    certs
+}
+
+/// Loads certificate and key from the directory, `None` if files are missing.
+fn load_pair(dir: &Path) -> anyhow::Result<Option<Arc<CertifiedKey>>> {
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+    let chain = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("can't read {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path).with_context(|| format!("can't read {}", key_path.display()))?;
+    let provider = CryptoProvider::get_default().context("crypto provider is not installed")?;
+    let cert = CertifiedKey::from_der(chain, key, provider)?;
+    Ok(Some(Arc::new(cert)))
+}
+
+fn read_chain(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let chain = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("can't read {}", path.display()))?;
+    anyhow::ensure!(!chain.is_empty(), "no certificates in {}", path.display());
+    // pingora panics on certificates it can't parse
+    for cert in &chain {
+        x509_parser::parse_x509_certificate(cert).with_context(|| format!("invalid certificate in {}", path.display()))?;
+    }
+    Ok(chain.into_iter().map(|c| c.to_vec()).collect())
+}
+
+/// CA bundle used instead of system roots to verify upstream certificates.
+pub fn load_ca(path: &Path) -> anyhow::Result<Arc<CaType>> {
+    let certs: Vec<_> = read_chain(path)?.into_iter().map(|c| WrappedX509::new(c, parse_x509)).collect();
+    Ok(certs.into())
+}
+
+/// Client certificate chain and key presented to upstreams.
+pub fn load_client_cert(cert: &Path, key: &Path) -> anyhow::Result<Arc<CertKey>> {
+    let chain = read_chain(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).with_context(|| format!("can't read {}", key.display()))?;
+    Ok(Arc::new(CertKey::new(chain, key.secret_der().to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a new self-signed certificate for `name` into `dir`, returns its DER.
+    fn write_cert(dir: &Path, name: &str) -> Vec<u8> {
+        std::fs::create_dir_all(dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        std::fs::write(dir.join(CERT_FILE), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join(KEY_FILE), cert.signing_key.serialize_pem()).unwrap();
+        cert.cert.der().to_vec()
+    }
+
+    fn selected(resolver: &CertResolver, sni: Option<&str>) -> Option<Vec<u8>> {
+        resolver.certs.load().select(sni).map(|c| c.end_entity_cert().unwrap().to_vec())
+    }
+
+    fn temp_dir() -> PathBuf {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        std::env::temp_dir().join(format!("simple-proxy-certs-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn exact_wins_over_wildcard_and_default() {
+        let dir = temp_dir();
+        let default = write_cert(&dir, "default.test");
+        let exact = write_cert(&dir.join("www.example.com"), "www.example.com");
+        let star = write_cert(&dir.join("*.example.com"), "*.example.com");
+        let underscore = write_cert(&dir.join("_.example.org"), "*.example.org");
+        let resolver = CertResolver::load(&dir);
+
+        assert_eq!(selected(&resolver, Some("www.example.com")), Some(exact.clone()));
+        assert_eq!(selected(&resolver, Some("WWW.Example.com")), Some(exact));
+        assert_eq!(selected(&resolver, Some("api.example.com")), Some(star));
+        assert_eq!(selected(&resolver, Some("api.example.org")), Some(underscore));
+        // wildcard covers one label only
+        assert_eq!(selected(&resolver, Some("a.b.example.com")), Some(default.clone()));
+        assert_eq!(selected(&resolver, Some("example.com")), Some(default.clone()));
+        assert_eq!(selected(&resolver, Some("other.test")), Some(default.clone()));
+        assert_eq!(selected(&resolver, None), Some(default));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_default_without_root_pair() {
+        let dir = temp_dir();
+        write_cert(&dir.join("www.example.com"), "www.example.com");
+        let resolver = CertResolver::load(&dir);
+        assert!(!resolver.is_empty());
+        assert_eq!(selected(&resolver, Some("other.test")), None);
+        assert_eq!(selected(&resolver, None), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload_replaces_certificates() {
+        let dir = temp_dir();
+        let old = write_cert(&dir.join("www.example.com"), "www.example.com");
+        let resolver = CertResolver::load(&dir);
+        assert_eq!(selected(&resolver, Some("www.example.com")), Some(old.clone()));
+
+        let new = write_cert(&dir.join("www.example.com"), "www.example.com");
+        assert_eq!(selected(&resolver, Some("www.example.com")), Some(old), "files are read on reload only");
+        resolver.reload();
+        assert_eq!(selected(&resolver, Some("www.example.com")), Some(new.clone()));
+
+        // empty directory keeps the previous certificates
+        std::fs::remove_dir_all(&dir).unwrap();
+        resolver.reload();
+        assert_eq!(selected(&resolver, Some("www.example.com")), Some(new));
+    }
+}
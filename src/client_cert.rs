@@ -0,0 +1,90 @@
+use std::any::Any;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use pingora::http::RequestHeader;
+use pingora::listeners::TlsAccept;
+use pingora::protocols::tls::TlsRef;
+use pingora::proxy::Session;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, pem::PemObject};
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use serde::{Deserialize, Serialize};
+
+const SUBJECT_HEADER: &str = "x-client-cert-subject";
+const FINGERPRINT_HEADER: &str = "x-client-cert-fingerprint";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Clients without certificate are accepted, records may still require one.
+    #[default]
+    Optional,
+    /// Handshake fails without valid certificate.
+    Required,
+}
+
+/// Verified client certificate of a tls connection.
+#[derive(Debug)]
+pub struct ClientCert {
+    /// RFC 4514 distinguished name.
+    pub subject: String,
+    /// Hex sha256 of the DER certificate.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let digest = pingora::tls::hash_certificate(&CertificateDer::from(der));
+        Some(Self {
+            subject: cert.subject().to_string(),
+            fingerprint: digest.iter().map(|b| format!("{b:02x}")).collect(),
+        })
+    }
+
+    pub fn from_session(session: &Session) -> Option<&Self> {
+        session.digest()?.ssl_digest.as_ref()?.extension.get::<Self>()
+    }
+}
+
+/// Verifier of client certificates signed by CA bundle in `ca`.
+pub fn verifier(ca: &Path, auth: ClientAuth) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).with_context(|| format!("can't read {}", ca.display()))? {
+        roots.add(cert.with_context(|| format!("can't read {}", ca.display()))?)?;
+    }
+    let provider = CryptoProvider::get_default().context("crypto provider is not installed")?;
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(provider));
+    let builder = match auth {
+        ClientAuth::Optional => builder.allow_unauthenticated(),
+        ClientAuth::Required => builder,
+    };
+    Ok(builder.build()?)
+}
+
+/// Keeps verified client certificate in tls digest of the connection.
+pub struct ClientCertCallbacks;
+
+#[async_trait]
+impl TlsAccept for ClientCertCallbacks {
+    async fn handshake_complete_callback(&self, tls: &TlsRef) -> Option<Arc<dyn Any + Send + Sync>> {
+        let cert = ClientCert::parse(tls.peer_certificate_der()?)?;
+        Some(Arc::new(cert))
+    }
+}
+
+/// Replaces client certificate headers, so clients can't send their own.
+pub fn set_headers(upstream: &mut RequestHeader, cert: Option<&ClientCert>) -> pingora::Result<()> {
+    upstream.remove_header(SUBJECT_HEADER);
+    upstream.remove_header(FINGERPRINT_HEADER);
+    if let Some(cert) = cert {
+        upstream.insert_header(SUBJECT_HEADER, &cert.subject)?;
+        upstream.insert_header(FINGERPRINT_HEADER, &cert.fingerprint)?;
+    }
+    Ok(())
+}
@@ -1,186 +1,727 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, ops::Range, path::PathBuf, sync::Arc, time::Duration};
 
-use hickory_resolver::{Resolver, TokioResolver, name_server::GenericConnector, proto::runtime::TokioRuntimeProvider};
+use arc_swap::ArcSwap;
+use hickory_resolver::{TokioResolver, name_server::GenericConnector, proto::runtime::TokioRuntimeProvider};
 use http::{uri::Authority};
-use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::tls::CaType;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::utils::tls::CertKey;
 use serde::{Deserialize, Serialize};
+use toml::Spanned;
 use tracing::{info, warn};
+use crate::balancer::{Balancer, BalancerSpec, LbStrategy};
+use crate::basic_auth::{BasicAuth, BasicAuthRecord};
+use crate::client_cert::ClientAuth;
+use crate::discovery::TokioResolverOpt;
+use crate::forward_auth::{ForwardAuth, ForwardAuthRecord};
+use crate::ip_filter::IpFilter;
+use crate::jwt::{Jwt, JwtRecord};
+use crate::proxy_protocol::ProxyProtocolVersion;
+use crate::rate_limit::{RateLimit, RateLimitRecord};
 use crate::wrap;
 
+/// Weighted strategies repeat a backend `weight` times, so it is kept small.
+const MAX_WEIGHT: usize = 256;
+
 
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct TcpRecord {
     pub domain: String,
-    pub upstream: String
+    pub upstream: String,
+    pub health_check: Option<HealthCheckRecord>,
+    /// Send PROXY header with client address to upstream.
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Networks allowed to connect, all if unset.
+    pub allow: Option<Vec<String>>,
+    /// Networks whose connections are dropped, checked before `allow`.
+    pub deny: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpParsedRecord {
+    pub upstream: String,
+    pub upstreams: Arc<Balancer>,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub ip_filter: Option<IpFilter>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// Backend is healthy if tcp connection can be established.
+    Tcp,
+    /// Backend is healthy if GET of `path` returns expected status.
+    #[default]
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 negotiated with ALPN, requires `https`.
+    H2,
+    /// HTTP/2 over plain tcp with prior knowledge.
+    H2c,
+    /// HTTP/2 with `https` or without it.
+    Grpc,
+}
+
+impl UpstreamProtocol {
+    pub fn is_h2(self) -> bool {
+        self != Self::Http1
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsRecord {
+    /// Verify upstream certificate and its name, default = true.
+    pub verify: Option<bool>,
+    /// PEM bundle used instead of system roots.
+    pub ca_file: Option<PathBuf>,
+    /// Name sent in SNI and verified, default is requested host.
+    pub sni: Option<String>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpstreamTls {
+    pub verify: bool,
+    pub ca: Option<Arc<CaType>>,
+    pub sni: Option<String>,
+    pub client_cert: Option<Arc<CertKey>>,
+}
+
+/// Compares certificate contents, so rotated files count as a change.
+impl PartialEq for UpstreamTls {
+    fn eq(&self, other: &Self) -> bool {
+        let ca = |tls: &Self| tls.ca.as_ref().map(|ca| ca.iter().map(|c| c.borrow_raw_cert().clone()).collect::<Vec<_>>());
+        let client_cert = |tls: &Self| {
+            tls.client_cert.as_ref().map(|c| {
+                let certs = std::iter::once(c.leaf()).chain(c.intermediates()).map(|c| c.borrow_raw_cert().clone());
+                (c.key().clone(), certs.collect::<Vec<_>>())
+            })
+        };
+        self.verify == other.verify
+            && self.sni == other.sni
+            && ca(self) == ca(other)
+            && client_cert(self) == client_cert(other)
+    }
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        Self { verify: true, ca: None, sni: None, client_cert: None }
+    }
+}
+
+impl UpstreamTls {
+    fn try_parse(record: UpstreamTlsRecord) -> Result<Self, String> {
+        let UpstreamTlsRecord { verify, ca_file, sni, client_cert, client_key } = record;
+        let ca = ca_file
+            .map(|path| crate::certs::load_ca(&path).map_err(|e| format!("Can't load ca_file: {e:#}")))
+            .transpose()?;
+        let client_cert = match (client_cert, client_key) {
+            (Some(cert), Some(key)) => Some(
+                crate::certs::load_client_cert(&cert, &key).map_err(|e| format!("Can't load client certificate: {e:#}"))?,
+            ),
+            (None, None) => None,
+            _ => return Err("client_cert and client_key have to be set together".to_string()),
+        };
+        if sni.as_ref().is_some_and(|s| s.is_empty()) {
+            return Err("Empty sni disables verification, use verify = false instead".to_string());
+        }
+        Ok(Self { verify: verify.unwrap_or(true), ca, sni, client_cert })
+    }
+
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        peer.options.verify_cert = self.verify;
+        peer.options.verify_hostname = self.verify;
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert.clone();
+        if let Some(sni) = &self.sni {
+            peer.sni = sni.clone();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckRecord {
+    /// Default is http for http records and tcp for tcp records.
+    pub kind: Option<HealthCheckKind>,
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub status: Option<u16>,
+    /// Seconds between checks.
+    pub interval: Option<u64>,
+    /// Seconds to wait for connect and response.
+    pub timeout: Option<u64>,
+    /// Consecutive successes to become healthy.
+    pub rise: Option<usize>,
+    /// Consecutive failures to become unhealthy.
+    pub fall: Option<usize>,
+}
+
+impl HealthCheckRecord {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(path) = &self.path && path.parse::<http::Uri>().is_err() {
+            return Err(format!("Can't parse health check path: {path}"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct HttpRecord {
     pub domain: String,
-    pub upstream: String,
+    pub upstream: Option<String>,
+    pub upstreams: Option<Vec<UpstreamRecord>>,
+    pub lb: Option<LbStrategy>,
+    pub hash_header: Option<String>,
+    pub health_check: Option<HealthCheckRecord>,
     pub routes: Option<Vec<String>>,
     pub https: Option<bool>,
     pub proxy_ports_from_prefix: Option<Vec<u16>>,
     pub strip_route: Option<bool>,
+    /// Serve on plain http listener instead of redirecting to https.
+    pub plain_http: Option<bool>,
+    /// Send PROXY header with client address to upstream, such connections are not reused.
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Seconds upgraded (e.g. WebSocket) connection may stay without traffic in either direction.
+    pub upgrade_idle_timeout: Option<u64>,
+    /// Seconds upgraded connection may stay open at all.
+    pub upgrade_max_lifetime: Option<u64>,
+    /// Protocol spoken to upstreams, default is HTTP/1.1.
+    pub protocol: Option<UpstreamProtocol>,
+    /// Certificate verification and client certificate for `https` upstreams.
+    pub upstream_tls: Option<UpstreamTlsRecord>,
+    /// Require certificate verified by listener `client_ca`, requests without it get 403.
+    pub client_cert: Option<bool>,
+    /// Requests over the limit get 429 with `Retry-After`.
+    pub rate_limit: Option<RateLimitRecord>,
+    /// Networks allowed to use the record, others get 403.
+    pub allow: Option<Vec<String>>,
+    /// Networks getting 403, checked before `allow`.
+    pub deny: Option<Vec<String>>,
+    /// Basic authentication, requests without valid credentials get 401.
+    pub auth: Option<BasicAuthRecord>,
+    /// Auth service asked about every request before proxying.
+    pub forward_auth: Option<ForwardAuthRecord>,
+    /// Bearer token validation, requests without valid token get 401.
+    pub jwt: Option<JwtRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum UpstreamRecord {
+    Addr(String),
+    Weighted { addr: String, weight: usize },
 }
 
 
 
 impl HttpParsedRecord {
+    /// Balancer of `previous` config is reused if upstreams and health check are unchanged.
     async fn try_parse(
-        resolver: &Option<Resolver<GenericConnector<TokioRuntimeProvider>>>,
-        upstream: String, 
-        https: bool, 
-        proxy_ports_from_prefix: Option<Vec<u16>>, 
-        routes: Option<Vec<String>>,
-        strip_route: Option<bool>,
-    ) -> Option<Self> {
-        let Ok(mut authority) = upstream.parse() else {
-            warn!("Can't parse upstream to authority: {}, skipping", upstream);
-            return None;
+        resolver: &TokioResolverOpt,
+        dns_refresh: Option<Duration>,
+        previous: &[Arc<Balancer>],
+        record: HttpRecord,
+    ) -> Result<Self, String> {
+        let HttpRecord {
+            domain,
+            upstream,
+            upstreams,
+            lb,
+            hash_header,
+            health_check,
+            https,
+            proxy_ports_from_prefix,
+            routes,
+            strip_route,
+            plain_http,
+            proxy_protocol,
+            upgrade_idle_timeout,
+            upgrade_max_lifetime,
+            protocol,
+            upstream_tls,
+            client_cert,
+            rate_limit,
+            allow,
+            deny,
+            auth,
+            forward_auth,
+            jwt,
+        } = record;
+        let https = https.unwrap_or(false);
+        let protocol = protocol.unwrap_or_default();
+        match protocol {
+            UpstreamProtocol::H2 if !https => return Err("protocol h2 requires https, use h2c for plain upstreams".to_string()),
+            UpstreamProtocol::H2c if https => return Err("protocol h2c can't be used with https, use h2".to_string()),
+            _ => {}
+        }
+        health_check.as_ref().map(HealthCheckRecord::validate).transpose()?;
+        if upgrade_idle_timeout == Some(0) || upgrade_max_lifetime == Some(0) {
+            return Err("Upgrade timeouts must be at least one second".to_string());
+        }
+        let rate_limit = rate_limit.map(RateLimit::try_parse).transpose()?.map(Arc::new);
+        let ip_filter = IpFilter::try_parse(allow, deny)?;
+        if auth.is_some() && jwt.is_some() {
+            return Err("auth and jwt both read Authorization header, set only one of them".to_string());
+        }
+        let auth = auth.map(BasicAuth::try_parse).transpose()?.map(Arc::new);
+        let jwt = jwt.map(Jwt::try_parse).transpose()?.map(Arc::new);
+        let forward_auth = match forward_auth {
+            Some(record) => Some(Arc::new(ForwardAuth::try_parse(resolver, dns_refresh, record).await?)),
+            None => None,
         };
-        let maybe_addr = upstream.parse::<core::net::SocketAddr>();
-        let addr = match maybe_addr {
-            Ok(addr) => SocketAddr::Inet(addr),
-            Err(_) => {
-                let Some(resolver) = resolver else {
-                    warn!("Can't parse upstream to socket: {upstream}, skipping");  
-                    return None;
-                };
-                let Some((hostname, port)) = upstream.rsplit_once(":") else {
-                    warn!("Can't parse upstream to socket: {upstream}, skipping");
-                    return None;
-                };
-                let Ok(port) = port.parse() else {
-                    warn!("Can't parse port for {upstream}");
-                    return None;
-                };
-                info!("Resolving {}", hostname);
-                let ip = match resolver.lookup_ip(hostname).await {
-                    Ok(v) => v,
-                    Err(e) => {
-                        warn!("Failed to resolve ip addr for {hostname}: {e}");
-                        return None;
-                    }
-                };
-                let Some(addr) = ip.iter().next() else {
-                    warn!("Failed to resolve ip addr for 
-                    {hostname}");
-                    return None;
-                };
-                let addr = SocketAddr::Inet(std::net::SocketAddr::new(addr, port));
-                authority = addr.to_string().parse()
-                    .inspect_err(|_| warn!("Can't parse upstream to authority: {}, skipping", upstream))
-                    .ok()?;
-                info!("Resolved {upstream} to {addr}");
-                addr
+        let upstreams: Vec<_> = upstream
+            .map(UpstreamRecord::Addr)
+            .into_iter()
+            .chain(upstreams.unwrap_or_default())
+            .collect();
+        if upstreams.is_empty() {
+            return Err("No upstream set, at least one of upstream or upstreams is required".to_string());
+        }
+        let mut addrs = Vec::new();
+        for upstream in upstreams {
+            let (upstream, weight) = match upstream {
+                UpstreamRecord::Addr(addr) => (addr, 1),
+                UpstreamRecord::Weighted { addr, weight } => (addr, weight),
+            };
+            if !(1..=MAX_WEIGHT).contains(&weight) {
+                return Err(format!("Weight of upstream {upstream} must be between 1 and {MAX_WEIGHT}"));
+            }
+            if upstream.parse::<Authority>().is_err() {
+                return Err(format!("Can't parse upstream to authority: {upstream}"));
             }
+            addrs.push((upstream, weight));
+        }
+
+        let upstream_tls = match (https, upstream_tls) {
+            (true, record) => Some(record.map(UpstreamTls::try_parse).transpose()?.unwrap_or_default()),
+            (false, None) => None,
+            (false, Some(_)) => return Err("upstream_tls requires https".to_string()),
         };
-        
-        Some(HttpParsedRecord {
-            upstream: authority,
-            addr,
+        let spec = BalancerSpec {
+            name: format!("http {domain}"),
+            strategy: lb.unwrap_or_default(),
+            upstreams: addrs,
+            dns_refresh,
+            health_check: health_check.map(|hc| (hc, domain.clone(), upstream_tls.clone())),
+        };
+        Ok(HttpParsedRecord {
+            upstreams: Balancer::carry_over(previous, resolver.clone(), spec).await?,
+            hash_header,
             strip_route: strip_route.unwrap_or(false),
-            routes: routes
-                        .unwrap_or_default()
-                        .into_iter()
-                        .filter(|r| !(r.is_empty() || r == "/"))
-                        .collect(),
+            plain_http: plain_http.unwrap_or(false),
+            proxy_protocol,
+            upgrade_idle_timeout: upgrade_idle_timeout.map(Duration::from_secs),
+            upgrade_max_lifetime: upgrade_max_lifetime.map(Duration::from_secs),
+            protocol,
+            upstream_tls,
+            client_cert: client_cert.unwrap_or(false),
+            rate_limit,
+            ip_filter,
+            auth,
+            forward_auth,
+            jwt,
+            routes: normalize_routes(routes),
             https,
             proxy_ports_from_prefix, 
         })
     }
 }
 
+impl TcpParsedRecord {
+    async fn try_parse(
+        resolver: &TokioResolverOpt,
+        dns_refresh: Option<Duration>,
+        previous: &[Arc<Balancer>],
+        port: u16,
+        record: TcpRecord,
+    ) -> Result<Self, String> {
+        let TcpRecord { domain, upstream, health_check, proxy_protocol, allow, deny } = record;
+        health_check.as_ref().map(HealthCheckRecord::validate).transpose()?;
+        let ip_filter = IpFilter::try_parse(allow, deny)?;
+        let health_check = health_check.map(|hc| HealthCheckRecord { kind: Some(hc.kind.unwrap_or(HealthCheckKind::Tcp)), ..hc });
+        let spec = BalancerSpec {
+            name: format!("tcp {domain} on port {port}"),
+            strategy: LbStrategy::RoundRobin,
+            upstreams: vec![(upstream.clone(), 1)],
+            dns_refresh,
+            health_check: health_check.map(|hc| (hc, domain, None)),
+        };
+        let upstreams = Balancer::carry_over(previous, resolver.clone(), spec).await?;
+        Ok(TcpParsedRecord { upstream, upstreams, proxy_protocol, ip_filter })
+    }
+}
+
+fn normalize_routes(routes: Option<Vec<String>>) -> Vec<String> {
+    routes
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| !(r.is_empty() || r == "/"))
+        .collect()
+}
+
 
 #[derive(Clone, Debug)]
 pub struct HttpParsedRecord {
-    #[allow(unused)]
-    pub upstream: Authority,
-    pub addr: SocketAddr,
+    pub upstreams: Arc<Balancer>,
+    /// Header used as hash key by hashing strategies instead of client ip.
+    pub hash_header: Option<String>,
     pub routes: Vec<String>,
     pub https: bool,
     pub strip_route: bool,
     pub proxy_ports_from_prefix: Option<Vec<u16>>,
+    pub plain_http: bool,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub upgrade_idle_timeout: Option<Duration>,
+    pub upgrade_max_lifetime: Option<Duration>,
+    pub protocol: UpstreamProtocol,
+    /// Set for `https` records.
+    pub upstream_tls: Option<UpstreamTls>,
+    pub client_cert: bool,
+    pub rate_limit: Option<Arc<RateLimit>>,
+    pub ip_filter: Option<IpFilter>,
+    pub auth: Option<Arc<BasicAuth>>,
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    pub jwt: Option<Arc<Jwt>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct DirRecord {
     pub domain: String,
     pub listen: String,
     pub path: PathBuf,
-    pub route: String
+    pub route: String,
+    /// Requests over the limit get 429 with `Retry-After`.
+    pub rate_limit: Option<RateLimitRecord>,
+    /// Networks allowed to use the record, others get 403.
+    pub allow: Option<Vec<String>>,
+    /// Networks getting 403, checked before `allow`.
+    pub deny: Option<Vec<String>>,
+    /// Basic authentication, requests without valid credentials get 401.
+    pub auth: Option<BasicAuthRecord>,
 }
 
+/// Policies are applied by http gateway, dir servers only use path and route.
 #[derive(Clone, Debug)]
 pub struct DirParsedRecord {
     // pub domain: String,
     pub listen: std::net::SocketAddr,
     pub path: PathBuf,
-    pub route: String
+    pub route: String,
+    pub rate_limit: Option<Arc<RateLimit>>,
+    pub ip_filter: Option<IpFilter>,
+    pub auth: Option<Arc<BasicAuth>>,
 }
 
+impl DirParsedRecord {
+    fn try_parse(record: DirRecord) -> Result<Self, String> {
+        let DirRecord { domain: _, listen, path, route, rate_limit, allow, deny, auth } = record;
+        let Ok(listen) = listen.parse::<std::net::SocketAddr>() else {
+            return Err(format!("Can't parse listen to socket: {listen}"));
+        };
+        Ok(Self {
+            listen,
+            path,
+            route,
+            rate_limit: rate_limit.map(RateLimit::try_parse).transpose()?.map(Arc::new),
+            ip_filter: IpFilter::try_parse(allow, deny)?,
+            auth: auth.map(BasicAuth::try_parse).transpose()?.map(Arc::new),
+        })
+    }
+}
 
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerRecord {
+    pub addr: String,
+    pub tls: Option<bool>,
+    /// Certificate directory, `CERT_PATH` if unset.
+    pub cert_path: Option<String>,
+    /// Domains accepted on this listener, all if unset.
+    pub domains: Option<Vec<String>>,
+    /// Expect PROXY v1/v2 header from load balancer on every connection.
+    pub proxy_protocol: Option<bool>,
+    /// CA bundle verifying client certificates, tls listeners only.
+    pub client_ca: Option<String>,
+    pub client_auth: Option<ClientAuth>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerParsedRecord {
+    pub addr: std::net::SocketAddr,
+    pub tls: bool,
+    pub cert_path: String,
+    pub domains: Option<HashSet<String>>,
+    pub proxy_protocol: bool,
+    pub client_ca: Option<String>,
+    pub client_auth: ClientAuth,
+}
+
+impl ListenerParsedRecord {
+    /// Listener built from `LISTEN_ADDR` and `HTTPS` when config has none.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            addr: crate::CFG.LISTEN_ADDR.parse().map_err(|e| anyhow::anyhow!("Can't parse LISTEN_ADDR {}: {e}", crate::CFG.LISTEN_ADDR))?,
+            tls: crate::CFG.HTTPS,
+            cert_path: crate::CFG.CERT_PATH.clone(),
+            domains: None,
+            proxy_protocol: false,
+            client_ca: None,
+            client_auth: ClientAuth::default(),
+        })
+    }
+
+    pub fn accepts(&self, host: &str) -> bool {
+        self.domains.as_ref().is_none_or(|d| d.contains(host))
+    }
+}
+
+/// Problem found while turning config into routes.
+/// Points to the record (or tcp key) it was found in.
+#[derive(Clone, Debug)]
+pub struct ConfigIssue {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// Whether the record was left out of routes, conflicts only warn.
+    pub skipped: bool,
+}
+
+impl ConfigIssue {
+    fn new(source: &str, span: Range<usize>, message: impl Into<String>) -> Self {
+        let (line, column) = line_column(source, span.start);
+        Self { line, column, message: message.into(), skipped: true }
+    }
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// 1-based line and column of a byte offset.
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    (line, column)
+}
+
+/// Whether every request matched by `later` routes is matched by `earlier` first.
+fn routes_cover(earlier: &[String], later: &[String]) -> bool {
+    earlier.is_empty() || (!later.is_empty() && later.iter().all(|l| earlier.iter().any(|e| l.starts_with(e.as_str()))))
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigRecord {
     #[serde(default)]
-    tcp: HashMap<String, Vec<TcpRecord>>,
+    tcp: HashMap<Spanned<String>, Vec<Spanned<TcpRecord>>>,
     #[serde(default)]
-    http: Vec<HttpRecord>,
+    http: Vec<Spanned<HttpRecord>>,
     #[serde(default)]
-    dir: Vec<DirRecord>
+    dir: Vec<Spanned<DirRecord>>,
+    #[serde(default)]
+    listener: Vec<Spanned<ListenerRecord>>,
+    #[serde(skip)]
+    source: String,
 }
 
 impl ConfigRecord {
     pub fn from_file(path: &str) -> anyhow::Result<ConfigRecord> {
-        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+        Self::parse(std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: String) -> anyhow::Result<ConfigRecord> {
+        let mut record: ConfigRecord = toml::from_str(&source)?;
+        record.source = source;
+        Ok(record)
     }
 
-    pub async fn to_route_config(self) -> RouteConfig {
+    /// Finds records which can never be matched or clash with each other.
+    fn conflicts(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let issue = |span: Range<usize>, message: String| ConfigIssue { skipped: false, ..ConfigIssue::new(&self.source, span, message) };
+
+        let dirs: Vec<_> = self.dir.iter().map(|r| (r.span(), r.get_ref())).collect();
+        for (j, (span, later)) in dirs.iter().enumerate() {
+            for (earlier_span, earlier) in dirs[..j].iter() {
+                let (line, _) = line_column(&self.source, earlier_span.start);
+                if later.listen == earlier.listen && later.route == earlier.route {
+                    let what = if later.route == "/" { "root".to_string() } else { format!("route {}", later.route) };
+                    issues.push(issue(span.clone(), format!(
+                        "Duplicate dir {what} on {}, already defined at line {line}", later.listen
+                    )));
+                } else if later.domain.eq_ignore_ascii_case(&earlier.domain) && later.route.starts_with(&earlier.route) {
+                    issues.push(issue(span.clone(), format!(
+                        "Dir route {} for {} is shadowed by dir route {} at line {line}", later.route, later.domain, earlier.route
+                    )));
+                }
+            }
+        }
+
+        let http: Vec<_> = self.http.iter()
+            .map(|r| (r.span(), r.get_ref(), normalize_routes(r.get_ref().routes.clone())))
+            .collect();
+        for (j, (span, later, later_routes)) in http.iter().enumerate() {
+            let verified_on = |l: &ListenerRecord| {
+                l.client_ca.is_some()
+                    && l.domains.as_ref().is_none_or(|d| d.iter().any(|d| d.eq_ignore_ascii_case(&later.domain)))
+            };
+            if later.client_cert == Some(true) && !self.listener.iter().any(|l| verified_on(l.get_ref())) {
+                issues.push(issue(span.clone(), format!(
+                    "Http record for {} requires client_cert, but no listener with client_ca accepts it", later.domain
+                )));
+            }
+            if later.proxy_ports_from_prefix.is_none() {
+                let dir = dirs.iter().find(|(_, d)| {
+                    d.domain.eq_ignore_ascii_case(&later.domain) && (d.route == "/" || routes_cover(std::slice::from_ref(&d.route), later_routes))
+                });
+                if let Some((dir_span, dir)) = dir {
+                    let (line, _) = line_column(&self.source, dir_span.start);
+                    issues.push(issue(span.clone(), format!(
+                        "Http record for {} is shadowed by dir route {} at line {line}", later.domain, dir.route
+                    )));
+                    continue;
+                }
+            }
+            // plain http listener only sees plain_http records, so later plain record
+            // is still reachable there behind earlier https-only one
+            let shadowed_by = http[..j].iter().find(|(_, earlier, earlier_routes)| {
+                earlier.domain.eq_ignore_ascii_case(&later.domain)
+                    && earlier.proxy_ports_from_prefix == later.proxy_ports_from_prefix
+                    && (earlier.plain_http == Some(true) || later.plain_http != Some(true))
+                    && routes_cover(earlier_routes, later_routes)
+            });
+            if let Some((earlier_span, _, _)) = shadowed_by {
+                let (line, _) = line_column(&self.source, earlier_span.start);
+                issues.push(issue(span.clone(), format!(
+                    "Http record for {} is shadowed by record at line {line}", later.domain
+                )));
+            }
+        }
+        issues
+    }
+
+    /// Resolves config into routes. Records with problems are skipped
+    /// and reported in returned issues. Unchanged balancers of `previous`
+    /// config are reused, so reload keeps health of their backends.
+    #[allow(clippy::wrong_self_convention)]
+    pub async fn to_route_config(self, previous: Option<&RouteConfig>) -> (RouteConfig, Vec<ConfigIssue>) {
+        let mut issues = self.conflicts();
+        let previous = previous.map(RouteConfig::balancers).unwrap_or_default();
+        let source = self.source;
+        let issue = |span: Range<usize>, message: String| ConfigIssue::new(&source, span, message);
+
+        // answers are cached at most for refresh interval, otherwise until their TTL expires
+        let dns_refresh = (crate::CFG.DNS_REFRESH_INTERVAL > 0).then(|| Duration::from_secs(crate::CFG.DNS_REFRESH_INTERVAL));
         let resolver = TokioResolver::builder(
             GenericConnector::new(TokioRuntimeProvider::default()))
-                    .map(|builder| builder.build());
+                    .map(|mut builder| {
+                        if dns_refresh.is_some() {
+                            builder.options_mut().positive_max_ttl = dns_refresh;
+                        }
+                        builder.build()
+                    });
         let resolver = resolver.inspect_err(|e|warn!("Failed to create resolver: {e}")).ok();
         if resolver.is_some(){info!("Resolver created!");}
 
         let mut parsed_tcp = HashMap::new();
         for (k, v) in self.tcp {
-            let Some(k) = k.parse().ok() else { warn!("Can't parse port from {k}! Skipping..."); continue };
+            let Ok(port) = k.get_ref().parse() else {
+                issues.push(issue(k.span(), format!("Can't parse port from {}", k.get_ref())));
+                continue
+            };
             let mut inner = HashMap::new();
             for r in v {
-                inner.insert(r.domain.clone(), r);
+                let span = r.span();
+                let r = r.into_inner();
+                let domain = r.domain.to_ascii_lowercase();
+                if inner.contains_key(&domain) {
+                    issues.push(issue(span, format!("Duplicate tcp domain {domain} on port {port}")));
+                    continue;
+                }
+                match TcpParsedRecord::try_parse(&resolver, dns_refresh, &previous, port, r).await {
+                    Ok(parsed) => { inner.insert(domain, parsed); },
+                    Err(e) => issues.push(issue(span, e)),
+                }
             }
-            parsed_tcp.insert(k, inner);
+            parsed_tcp.insert(port, inner);
         }
         let mut http_records: HashMap<String, Vec<HttpParsedRecord>> = HashMap::new();
         for record in self.http {
-            let HttpRecord {
-                domain, 
-                upstream, 
-                https, 
-                proxy_ports_from_prefix,
-                routes,
-                strip_route,
-                ..
-            } = record;
-            let parsed = HttpParsedRecord::try_parse(
-                &resolver,
-                upstream, 
-                https.unwrap_or(false), 
-                proxy_ports_from_prefix,
-                routes,
-                strip_route,
-            ).await;
-            let Some(parsed) = parsed else { continue };
-            http_records.entry(domain).or_default().push(parsed);
+            let span = record.span();
+            let record = record.into_inner();
+            let domain = record.domain.to_ascii_lowercase();
+            match HttpParsedRecord::try_parse(&resolver, dns_refresh, &previous, record).await {
+                Ok(parsed) => http_records.entry(domain).or_default().push(parsed),
+                Err(e) => issues.push(issue(span, e)),
+            }
+        }
+        let mut dirs = Vec::new();
+        for record in self.dir {
+            let span = record.span();
+            let record = record.into_inner();
+            let domain = record.domain.to_ascii_lowercase();
+            match DirParsedRecord::try_parse(record) {
+                Ok(parsed) => dirs.push((domain, parsed)),
+                Err(e) => issues.push(issue(span, e)),
+            }
+        }
+        let mut listeners: Vec<ListenerParsedRecord> = Vec::new();
+        for record in self.listener {
+            let span = record.span();
+            let record = record.into_inner();
+            let Ok(addr) = record.addr.parse::<std::net::SocketAddr>() else {
+                issues.push(issue(span, format!("Can't parse listener addr to socket: {}", record.addr)));
+                continue;
+            };
+            if listeners.iter().any(|l| l.addr == addr) {
+                issues.push(issue(span, format!("Duplicate listener {addr}")));
+                continue;
+            }
+            if record.client_ca.is_some() && record.tls != Some(true) {
+                issues.push(issue(span, format!("Listener {addr} needs tls to verify client certificates")));
+                continue;
+            }
+            if record.client_auth.is_some() && record.client_ca.is_none() {
+                issues.push(issue(span, format!("Listener {addr} has client_auth without client_ca")));
+                continue;
+            }
+            listeners.push(ListenerParsedRecord {
+                addr,
+                tls: record.tls.unwrap_or(false),
+                cert_path: record.cert_path.unwrap_or_else(|| crate::CFG.CERT_PATH.clone()),
+                domains: record.domains.map(|d| d.into_iter().map(|d| d.to_ascii_lowercase()).collect()),
+                proxy_protocol: record.proxy_protocol.unwrap_or(false),
+                client_ca: record.client_ca,
+                client_auth: record.client_auth.unwrap_or_default(),
+            });
         }
-        RouteConfig {
+        issues.sort_by_key(|i| (i.line, i.column));
+        let config = RouteConfig {
             tcp: TcpConfig(parsed_tcp),
             http: HttpConfig(http_records),
-            dir: DirConfig::from_record(self.dir)
-        }
+            dir: DirConfig::from_record(dirs),
+            listener: listeners,
+        };
+        (config, issues)
     }
 }
 
@@ -190,10 +731,29 @@ impl ConfigRecord {
 pub struct RouteConfig {
     pub tcp: TcpConfig,
     pub http: HttpConfig,
-    pub dir: DirConfig
+    pub dir: DirConfig,
+    /// Empty when listener is taken from `LISTEN_ADDR`.
+    pub listener: Vec<ListenerParsedRecord>,
 }
 
-wrap!(pub TcpConfig(pub HashMap<u16, HashMap<String, TcpRecord>>) = Default, Debug, Clone);
+impl RouteConfig {
+    /// Whether any http or dir record is defined for the domain.
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.http.contains_key(domain) || self.dir.domain.contains_key(domain)
+    }
+
+    /// Upstream balancers of http and tcp records.
+    fn balancers(&self) -> Vec<Arc<Balancer>> {
+        let http = self.http.values().flatten().map(|r| Arc::clone(&r.upstreams));
+        let tcp = self.tcp.values().flat_map(|r| r.values()).map(|r| Arc::clone(&r.upstreams));
+        http.chain(tcp).collect()
+    }
+}
+
+/// Route config shared between services, swapped as a whole on reload.
+pub type SharedConfig = Arc<ArcSwap<RouteConfig>>;
+
+wrap!(pub TcpConfig(pub HashMap<u16, HashMap<String, TcpParsedRecord>>) = Default, Debug, Clone);
 wrap!(pub HttpConfig(pub HashMap<String, Vec<HttpParsedRecord>>) = Default, Debug, Clone);
 // wrap!(pub DirConfig(pub HashMap<String, Vec<DirParsedRecord>>) = Default, Debug, Clone);
 
@@ -205,29 +765,96 @@ pub struct DirConfig {
 
 
 impl DirConfig {
-    pub fn from_record(dir: Vec<DirRecord>) -> DirConfig {
+    /// Records by domain, both maps share policies of a record.
+    pub fn from_record(dir: Vec<(String, DirParsedRecord)>) -> DirConfig {
         let mut domain: HashMap<String, Vec<DirParsedRecord>> = HashMap::new();
         let mut listen: HashMap<std::net::SocketAddr, Vec<DirParsedRecord>> = HashMap::new();
-        for r in dir {
-            let l: Result<std::net::SocketAddr, std::net::AddrParseError> = r.listen.parse();
-            let Ok(listen_addr) = l else {
-                warn!("Can't parse listen to socket: {}, skipping", r.listen);
-                continue;
-            };
-            domain.entry(r.domain.clone()).or_default().push(DirParsedRecord {
-                // domain: r.domain.clone(),
-                listen: listen_addr.clone(),
-                path: r.path.clone(),
-                route: r.route.clone()
-            });
-            listen.entry(listen_addr).or_default().push(DirParsedRecord {
-                // domain: r.domain,
-                listen: listen_addr,
-                path: r.path,
-                route: r.route
-            });
+        for (d, r) in dir {
+            listen.entry(r.listen).or_default().push(r.clone());
+            domain.entry(d).or_default().push(r);
         }
         DirConfig { domain, listen }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines and messages of conflicts found in `source`.
+    fn conflicts(source: &str) -> Vec<(usize, String)> {
+        ConfigRecord::parse(source.to_string()).unwrap().conflicts().into_iter().map(|i| (i.line, i.message)).collect()
+    }
+
+    #[test]
+    fn duplicate_dirs_are_reported() {
+        let issues = conflicts(r#"
+[[dir]]
+domain = "example.com"
+listen = "127.0.0.1:5990"
+path = "/srv/a"
+route = "/"
+
+[[dir]]
+domain = "EXAMPLE.com"
+listen = "127.0.0.1:5990"
+path = "/srv/b"
+route = "/"
+"#);
+        assert_eq!(issues, vec![(8, "Duplicate dir root on 127.0.0.1:5990, already defined at line 2".to_string())]);
+    }
+
+    #[test]
+    fn shadowed_records_are_reported_regardless_of_case() {
+        let issues = conflicts(r#"
+[[http]]
+domain = "Example.com"
+upstream = "127.0.0.1:5700"
+
+[[http]]
+domain = "example.com"
+upstream = "127.0.0.1:5701"
+routes = ["/api"]
+
+[[dir]]
+domain = "static.example.com"
+listen = "127.0.0.1:5990"
+path = "/srv"
+route = "/assets"
+
+[[http]]
+domain = "STATIC.example.com"
+upstream = "127.0.0.1:5702"
+routes = ["/assets/img"]
+"#);
+        assert_eq!(issues, vec![
+            (6, "Http record for example.com is shadowed by record at line 2".to_string()),
+            (17, "Http record for STATIC.example.com is shadowed by dir route /assets at line 11".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn reachable_records_are_not_reported() {
+        let issues = conflicts(r#"
+[[http]]
+domain = "example.com"
+upstream = "127.0.0.1:5700"
+routes = ["/api"]
+
+[[http]]
+domain = "example.com"
+upstream = "127.0.0.1:5701"
+
+[[http]]
+domain = "other.com"
+upstream = "127.0.0.1:5702"
+
+[[http]]
+domain = "other.com"
+upstream = "127.0.0.1:5703"
+plain_http = true
+"#);
+        assert_eq!(issues, vec![]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hickory_resolver::{Resolver, name_server::GenericConnector, proto::runtime::TokioRuntimeProvider};
+use pingora::lb::{Backend, discovery::ServiceDiscovery};
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::{Error, ErrorType};
+use tracing::info;
+
+pub type TokioResolverOpt = Option<Resolver<GenericConnector<TokioRuntimeProvider>>>;
+
+/// Shortest time between resolutions, TTL of 0 would mean resolving on every tick.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+/// Delay before the next attempt after failed resolution.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resolves `host:port` upstreams to every returned ip, ip upstreams are used as is.
+/// Resolution is repeated when the shortest TTL expires, or after `refresh` if it is set.
+pub struct DnsDiscovery {
+    name: String,
+    resolver: TokioResolverOpt,
+    upstreams: Vec<(String, usize)>,
+    refresh: Option<Duration>,
+    next_resolve: Arc<Mutex<Instant>>,
+    resolved: Mutex<BTreeSet<Backend>>,
+}
+
+impl DnsDiscovery {
+    /// `upstreams` are `host:port` addresses with weights, `name` is used in logs.
+    pub fn new(name: &str, resolver: TokioResolverOpt, upstreams: Vec<(String, usize)>, refresh: Option<Duration>) -> Self {
+        Self {
+            name: name.to_string(),
+            resolver,
+            upstreams,
+            refresh,
+            next_resolve: Arc::new(Mutex::new(Instant::now())),
+            resolved: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Time of the next resolution, shared with the balancer. `None` when
+    /// all upstreams are ips and there is nothing to re-resolve.
+    pub fn next_resolve(&self) -> Option<Arc<Mutex<Instant>>> {
+        self.upstreams
+            .iter()
+            .any(|(upstream, _)| upstream.parse::<std::net::SocketAddr>().is_err())
+            .then(|| Arc::clone(&self.next_resolve))
+    }
+
+    /// Resolves all upstreams, returns backends and the instant
+    /// until which the shortest-lived answer is valid.
+    async fn resolve(&self) -> Result<(BTreeSet<Backend>, Option<Instant>), String> {
+        let mut backends = BTreeSet::new();
+        let mut valid_until: Option<Instant> = None;
+        for (upstream, weight) in self.upstreams.iter() {
+            let (addrs, until) = resolve_upstream(&self.resolver, upstream).await?;
+            if let Some(until) = until {
+                valid_until = Some(valid_until.map_or(until, |v| v.min(until)));
+            }
+            backends.extend(addrs.into_iter().map(|addr| Backend { addr, weight: *weight, ext: Default::default() }));
+        }
+        Ok((backends, valid_until))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsDiscovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = Instant::now();
+        let (backends, valid_until) = match self.resolve().await {
+            Ok(v) => v,
+            Err(e) => {
+                *self.next_resolve.lock().unwrap() = now + RETRY_INTERVAL;
+                return Error::e_explain(ErrorType::InternalError, e);
+            }
+        };
+        let next = match (self.refresh, valid_until) {
+            (Some(refresh), _) => now + refresh,
+            (None, Some(until)) => until.max(now + MIN_REFRESH),
+            (None, None) => now + MIN_REFRESH,
+        };
+        *self.next_resolve.lock().unwrap() = next;
+
+        let mut resolved = self.resolved.lock().unwrap();
+        if *resolved != backends {
+            let addrs: Vec<_> = backends.iter().map(|b| b.addr.to_string()).collect();
+            if resolved.is_empty() {
+                info!("Upstreams of {} resolved to {}", self.name, addrs.join(", "));
+            } else {
+                info!("Upstreams of {} changed to {}", self.name, addrs.join(", "));
+            }
+            *resolved = backends.clone();
+        }
+        Ok((backends, HashMap::new()))
+    }
+}
+
+/// Resolves `host:port` upstream to socket addresses, hostnames are looked up
+/// with resolver and every returned ip is used together with answer expiration.
+async fn resolve_upstream(resolver: &TokioResolverOpt, upstream: &str) -> Result<(Vec<SocketAddr>, Option<Instant>), String> {
+    if let Ok(addr) = upstream.parse::<core::net::SocketAddr>() {
+        return Ok((vec![SocketAddr::Inet(addr)], None));
+    }
+    let Some(resolver) = resolver else {
+        return Err(format!("Can't parse upstream to socket: {upstream}"));
+    };
+    let Some((hostname, port)) = upstream.rsplit_once(":") else {
+        return Err(format!("Can't parse upstream to socket: {upstream}"));
+    };
+    let Ok(port) = port.parse() else {
+        return Err(format!("Can't parse port for {upstream}"));
+    };
+    let ips = resolver.lookup_ip(hostname).await
+        .map_err(|e| format!("Failed to resolve ip addr for {hostname}: {e}"))?;
+    let addrs: Vec<_> = ips.iter().map(|ip| SocketAddr::Inet(std::net::SocketAddr::new(ip, port))).collect();
+    if addrs.is_empty() {
+        return Err(format!("Failed to resolve ip addr for {hostname}"));
+    }
+    Ok((addrs, Some(ips.valid_until())))
+}
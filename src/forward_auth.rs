@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::{HeaderName, HeaderValue, Uri};
+use once_cell::sync::Lazy;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::balancer::{Balancer, BalancerSpec, LbStrategy};
+use crate::discovery::TokioResolverOpt;
+use crate::forwarded::{self, Hop};
+use crate::util::IpNets;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest denial body relayed to the client.
+const MAX_BODY: usize = 64 * 1024;
+const DEFAULT_REQUEST_HEADERS: [&str; 2] = ["cookie", "authorization"];
+/// Not relayed from denial responses, the gateway frames the body itself.
+const HOP_HEADERS: [&str; 4] = ["connection", "keep-alive", "transfer-encoding", "content-length"];
+/// Copied from client request so `forwarded::set_headers` extends chains of trusted proxies.
+const FORWARDED_HEADERS: [&str; 5] =
+    ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "x-forwarded-port", "forwarded"];
+
+/// Pools connections to auth services of all records.
+static CONNECTOR: Lazy<Connector> = Lazy::new(|| Connector::new(None));
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardAuthRecord {
+    /// Auth service endpoint, `http://` or `https://`.
+    pub url: String,
+    /// Client headers sent to auth service, default `Cookie` and `Authorization`.
+    pub request_headers: Option<Vec<String>>,
+    /// Auth response headers copied to upstream request when access is granted.
+    pub response_headers: Option<Vec<String>>,
+    /// Seconds to wait for auth service answer, default 5.
+    pub timeout: Option<u64>,
+}
+
+/// Result of auth subrequest.
+pub enum Verdict {
+    /// 2xx, with configured response headers to pass upstream.
+    Granted(Vec<(HeaderName, HeaderValue)>),
+    /// Any other answer, returned to client as is.
+    Denied(Box<ResponseHeader>, Bytes),
+}
+
+/// Auth service asked about every request of a record before it is proxied.
+#[derive(Debug)]
+pub struct ForwardAuth {
+    pub upstreams: Arc<Balancer>,
+    url: Uri,
+    https: bool,
+    request_headers: Vec<HeaderName>,
+    pub response_headers: Vec<HeaderName>,
+    timeout: Duration,
+}
+
+fn header_names(names: Option<Vec<String>>, default: &[&str]) -> Result<Vec<HeaderName>, String> {
+    names
+        .unwrap_or_else(|| default.iter().map(|n| n.to_string()).collect())
+        .iter()
+        .map(|n| n.parse().map_err(|_| format!("Invalid header name: {n}")))
+        .collect()
+}
+
+impl ForwardAuth {
+    pub async fn try_parse(
+        resolver: &TokioResolverOpt,
+        dns_refresh: Option<Duration>,
+        record: ForwardAuthRecord,
+    ) -> Result<Self, String> {
+        let ForwardAuthRecord { url, request_headers, response_headers, timeout } = record;
+        let uri = url.parse::<Uri>().map_err(|_| format!("Can't parse forward_auth url: {url}"))?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(format!("forward_auth url must be http or https: {url}")),
+        };
+        let Some(authority) = uri.authority() else {
+            return Err(format!("forward_auth url has no host: {url}"));
+        };
+        if timeout == Some(0) {
+            return Err("forward_auth timeout must be at least one second".to_string());
+        }
+        let addr = format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(if https { 443 } else { 80 }));
+        let spec = BalancerSpec {
+            name: format!("forward auth {url}"),
+            strategy: LbStrategy::RoundRobin,
+            upstreams: vec![(addr, 1)],
+            dns_refresh,
+            health_check: None,
+        };
+        let balancer = Balancer::new(resolver.clone(), spec).await?;
+        Ok(Self {
+            upstreams: Arc::new(balancer),
+            url: uri,
+            https,
+            request_headers: header_names(request_headers, &DEFAULT_REQUEST_HEADERS)?,
+            response_headers: header_names(response_headers, &[])?,
+            timeout: timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+        })
+    }
+
+    /// Asks auth service about the request, `uri` is the one requested by client.
+    pub async fn check(
+        &self,
+        req: &RequestHeader,
+        uri: &str,
+        hop: &Hop,
+        trusted: &IpNets,
+        request_id: &str,
+    ) -> pingora::Result<Verdict> {
+        match tokio::time::timeout(self.timeout, self.subrequest(req, uri, hop, trusted, request_id)).await {
+            Ok(verdict) => verdict,
+            Err(_) => Error::e_explain(ReadTimedout, format!("forward auth {} didn't answer in time", self.url)),
+        }
+    }
+
+    async fn subrequest(
+        &self,
+        req: &RequestHeader,
+        uri: &str,
+        hop: &Hop,
+        trusted: &IpNets,
+        request_id: &str,
+    ) -> pingora::Result<Verdict> {
+        let backend = self.upstreams.select(&[]).or_err(ConnectNoRoute, "no available forward auth upstream")?;
+        let host = self.url.host().unwrap_or_default();
+        let mut peer = HttpPeer::new(backend.addr, self.https, host.to_string());
+        peer.options.connection_timeout = Some(self.timeout);
+
+        let path = self.url.path_and_query().map_or("/", |pq| pq.as_str());
+        let mut auth_req = RequestHeader::build(req.method.clone(), path.as_bytes(), None)?;
+        auth_req.insert_header(http::header::HOST, self.url.authority().map_or(host, |a| a.as_str()))?;
+        for name in &self.request_headers {
+            for value in req.headers.get_all(name) {
+                auth_req.append_header(name.clone(), value)?;
+            }
+        }
+        for name in FORWARDED_HEADERS.into_iter().filter(|&n| !self.request_headers.iter().any(|h| h == n)) {
+            for value in req.headers.get_all(name) {
+                auth_req.append_header(name, value)?;
+            }
+        }
+        forwarded::set_headers(&mut auth_req, hop, trusted)?;
+        auth_req.insert_header("x-forwarded-method", req.method.as_str())?;
+        auth_req.insert_header("x-forwarded-uri", uri)?;
+        auth_req.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), request_id)?;
+        auth_req.insert_header(http::header::CONTENT_LENGTH, 0)?;
+
+        let (mut session, _) = CONNECTOR.get_http_session(&peer).await?;
+        session.write_request_header(Box::new(auth_req)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        let mut resp = session.response_header().expect("just read").clone();
+
+        let mut body = BytesMut::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            if body.len() + chunk.len() > MAX_BODY {
+                return Error::e_explain(InvalidHTTPHeader, format!("forward auth response body over {MAX_BODY} bytes"));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        CONNECTOR.release_http_session(session, &peer, None).await;
+
+        if resp.status.is_success() {
+            let headers = self
+                .response_headers
+                .iter()
+                .flat_map(|name| resp.headers.get_all(name).iter().map(|v| (name.clone(), v.clone())))
+                .collect();
+            return Ok(Verdict::Granted(headers));
+        }
+        for name in HOP_HEADERS {
+            resp.remove_header(name);
+        }
+        resp.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+        Ok(Verdict::Denied(Box::new(resp), body.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util;
+
+    /// Auth service answering every request with `response`, returns its url and last request head.
+    async fn stub(response: String) -> (String, Arc<Mutex<String>>) {
+        let (addr, request) = test_util::stub(move || response.clone()).await;
+        (format!("http://{addr}/verify"), request)
+    }
+
+    async fn forward_auth(url: String) -> ForwardAuth {
+        let record = ForwardAuthRecord {
+            url,
+            request_headers: None,
+            response_headers: Some(vec!["X-User".to_string()]),
+            timeout: Some(1),
+        };
+        ForwardAuth::try_parse(&None, None, record).await.unwrap()
+    }
+
+    async fn check(auth: &ForwardAuth) -> pingora::Result<Verdict> {
+        let mut req = RequestHeader::build("GET", b"/private?x=1", None).unwrap();
+        req.insert_header("cookie", "session=abc").unwrap();
+        req.insert_header("x-other", "not forwarded").unwrap();
+        check_from(auth, req, &IpNets::default()).await
+    }
+
+    async fn check_from(auth: &ForwardAuth, req: RequestHeader, trusted: &IpNets) -> pingora::Result<Verdict> {
+        let hop = Hop {
+            peer: Some("127.0.0.1".parse().unwrap()),
+            client_ip: Some("127.0.0.1".parse().unwrap()),
+            tls: false,
+            host: "app.test".to_string(),
+            port: Some(80),
+        };
+        auth.check(&req, "/private?x=1", &hop, trusted, "req-1").await
+    }
+
+    #[tokio::test]
+    async fn granted_copies_response_headers() {
+        let (url, request) = stub(
+            "HTTP/1.1 200 OK\r\nx-user: alice\r\nx-ignored: 1\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+        )
+        .await;
+        let auth = forward_auth(url).await;
+        let Verdict::Granted(headers) = check(&auth).await.unwrap() else { panic!("expected granted") };
+        assert_eq!(headers, vec![(HeaderName::from_static("x-user"), HeaderValue::from_static("alice"))]);
+
+        let request = request.lock().unwrap().clone();
+        assert!(request.starts_with("get /verify http/1.1\r\n"), "{request}");
+        assert!(request.contains("cookie: session=abc\r\n"));
+        assert!(!request.contains("x-other"));
+        assert!(request.contains("x-forwarded-method: get\r\n"));
+        assert!(request.contains("x-forwarded-uri: /private?x=1\r\n"));
+        assert!(request.contains("req-1"));
+    }
+
+    #[tokio::test]
+    async fn forwarded_chain_of_trusted_proxy_is_extended() {
+        let (url, request) =
+            stub("HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string()).await;
+        let auth = forward_auth(url).await;
+        let req = || {
+            let mut req = RequestHeader::build("GET", b"/private?x=1", None).unwrap();
+            req.insert_header("x-forwarded-for", "203.0.113.7").unwrap();
+            req.insert_header("x-forwarded-proto", "https").unwrap();
+            req.insert_header("forwarded", "for=203.0.113.7;proto=https").unwrap();
+            req
+        };
+
+        check_from(&auth, req(), &"127.0.0.1".parse().unwrap()).await.unwrap();
+        let head = request.lock().unwrap().clone();
+        assert!(head.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\r\n"), "{head}");
+        assert!(head.contains("x-forwarded-proto: https\r\n"), "{head}");
+        assert!(head.contains("forwarded: for=203.0.113.7;proto=https, for=127.0.0.1;proto=http"), "{head}");
+
+        check_from(&auth, req(), &IpNets::default()).await.unwrap();
+        let head = request.lock().unwrap().clone();
+        assert!(head.contains("x-forwarded-for: 127.0.0.1\r\n"), "{head}");
+        assert!(head.contains("x-forwarded-proto: http\r\n"), "{head}");
+        assert!(!head.contains("203.0.113.7"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn denied_is_passed_through() {
+        for status in ["401 Unauthorized", "403 Forbidden"] {
+            let response = format!(
+                "HTTP/1.1 {status}\r\nwww-authenticate: Bearer\r\ncontent-length: 6\r\nconnection: close\r\n\r\ndenied"
+            );
+            let (url, _) = stub(response).await;
+            let auth = forward_auth(url).await;
+            let Verdict::Denied(resp, body) = check(&auth).await.unwrap() else { panic!("expected denied") };
+            assert_eq!(resp.status.as_str(), &status[..3]);
+            assert_eq!(resp.headers.get("www-authenticate").unwrap(), "Bearer");
+            assert!(resp.headers.get("connection").is_none());
+            assert_eq!(resp.headers.get("content-length").unwrap(), "6");
+            assert_eq!(body, Bytes::from_static(b"denied"));
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_auth_service_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        drop(listener);
+        let auth = forward_auth(url).await;
+        assert!(check(&auth).await.is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::net::IpAddr;
+
+use pingora::http::RequestHeader;
+
+use crate::util::IpNets;
+
+/// Hop information added to forwarding headers.
+pub struct Hop {
+    /// Direct peer, `None` for unix socket clients.
+    pub peer: Option<IpAddr>,
+    pub client_ip: Option<IpAddr>,
+    pub tls: bool,
+    pub host: String,
+    /// Port the request was accepted on.
+    pub port: Option<u16>,
+}
+
+fn header_values(req: &RequestHeader, name: &str) -> Vec<String> {
+    req.headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Address of `for` parameter in `Forwarded` element, without quotes, brackets and port.
+fn forwarded_for(element: &str) -> String {
+    let node = element
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+        .map_or("", |(_, node)| node.trim().trim_matches('"'));
+    match node.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default().to_string(),
+        None => node.split(':').next().unwrap_or_default().to_string(),
+    }
+}
+
+/// Client ip reported by trusted proxy in `header`, direct peer otherwise.
+/// For `X-Forwarded-For` and `Forwarded` the rightmost address not in `trusted`
+/// is taken, so clients can't spoof it by sending their own header.
+pub fn real_ip(peer: IpAddr, req: &RequestHeader, header: &str, trusted: &IpNets) -> IpAddr {
+    if header.is_empty() || !trusted.contains(&peer) {
+        return peer;
+    }
+    let chain = if header.eq_ignore_ascii_case("x-forwarded-for") {
+        header_values(req, header)
+    } else if header.eq_ignore_ascii_case("forwarded") {
+        header_values(req, header).iter().map(|element| forwarded_for(element)).collect()
+    } else {
+        return req
+            .headers
+            .get(header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(peer);
+    };
+    let mut ip = peer;
+    for addr in chain.iter().rev() {
+        let Ok(addr) = addr.parse::<IpAddr>() else { break };
+        ip = addr;
+        if !trusted.contains(&addr) {
+            break;
+        }
+    }
+    ip
+}
+
+/// RFC 7239 node, ipv6 has to be quoted and bracketed.
+fn forwarded_node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+        None => "unknown".to_string(),
+    }
+}
+
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Sets `X-Forwarded-*`, `X-Real-Ip` and `Forwarded` headers. Chains sent by
+/// trusted proxies are extended, ones from other clients are replaced.
+pub fn set_headers(upstream: &mut RequestHeader, hop: &Hop, trusted: &IpNets) -> pingora::Result<()> {
+    let from_trusted = hop.peer.is_some_and(|peer| trusted.contains(&peer));
+    let proto = if hop.tls { "https" } else { "http" };
+
+    let mut xff = if from_trusted { header_values(upstream, "x-forwarded-for") } else { Vec::new() };
+    xff.extend(hop.peer.map(|p| p.to_string()));
+    if xff.is_empty() {
+        upstream.remove_header("x-forwarded-for");
+    } else {
+        upstream.insert_header("x-forwarded-for", xff.join(", "))?;
+    }
+
+    match hop.client_ip {
+        Some(ip) => upstream.insert_header("x-real-ip", ip.to_string())?,
+        None => {
+            upstream.remove_header("x-real-ip");
+        }
+    }
+
+    let keep = |upstream: &RequestHeader, name: &str| from_trusted && upstream.headers.contains_key(name);
+    if !keep(upstream, "x-forwarded-proto") {
+        upstream.insert_header("x-forwarded-proto", proto)?;
+    }
+    if !keep(upstream, "x-forwarded-host") {
+        if hop.host.is_empty() {
+            upstream.remove_header("x-forwarded-host");
+        } else {
+            upstream.insert_header("x-forwarded-host", &hop.host)?;
+        }
+    }
+    if !keep(upstream, "x-forwarded-port") {
+        match hop.port {
+            Some(port) => upstream.insert_header("x-forwarded-port", port.to_string())?,
+            None => {
+                upstream.remove_header("x-forwarded-port");
+            }
+        }
+    }
+
+    let mut forwarded = if from_trusted { header_values(upstream, "forwarded") } else { Vec::new() };
+    let mut element = format!("for={};proto={proto}", forwarded_node(hop.peer));
+    if !hop.host.is_empty() {
+        element.push_str(&format!(";host={}", quoted(&hop.host)));
+    }
+    forwarded.push(element);
+    upstream.insert_header("forwarded", forwarded.join(", "))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn trusted() -> IpNets {
+        "10.0.0.0/8, 192.0.2.1".parse().unwrap()
+    }
+
+    fn real_ip_of(peer: &str, header: &str, headers: &[(&str, &str)]) -> String {
+        real_ip(peer.parse().unwrap(), &request(headers), header, &trusted()).to_string()
+    }
+
+    #[test]
+    fn rightmost_untrusted_forwarded_for() {
+        let xff = [("x-forwarded-for", "203.0.113.9, 198.51.100.7, 10.0.0.2")];
+        assert_eq!(real_ip_of("10.0.0.1", "X-Forwarded-For", &xff), "198.51.100.7");
+        // chain split over several header lines
+        let xff = [("x-forwarded-for", "203.0.113.9"), ("x-forwarded-for", "192.0.2.1")];
+        assert_eq!(real_ip_of("10.0.0.1", "x-forwarded-for", &xff), "203.0.113.9");
+    }
+
+    #[test]
+    fn all_trusted_chain_gives_leftmost() {
+        let xff = [("x-forwarded-for", "10.0.0.3, 192.0.2.1")];
+        assert_eq!(real_ip_of("10.0.0.1", "x-forwarded-for", &xff), "10.0.0.3");
+        assert_eq!(real_ip_of("10.0.0.1", "x-forwarded-for", &[]), "10.0.0.1");
+    }
+
+    #[test]
+    fn garbage_stops_the_chain() {
+        let xff = [("x-forwarded-for", "203.0.113.9, nonsense, 10.0.0.2")];
+        assert_eq!(real_ip_of("10.0.0.1", "x-forwarded-for", &xff), "10.0.0.2");
+        let xff = [("x-forwarded-for", "203.0.113.9:1234")];
+        assert_eq!(real_ip_of("10.0.0.1", "x-forwarded-for", &xff), "10.0.0.1");
+        let single = [("cf-connecting-ip", "not an ip")];
+        assert_eq!(real_ip_of("10.0.0.1", "CF-Connecting-IP", &single), "10.0.0.1");
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let headers = [
+            ("x-forwarded-for", "203.0.113.9"),
+            ("forwarded", "for=203.0.113.9"),
+            ("cf-connecting-ip", "203.0.113.9"),
+        ];
+        for header in ["x-forwarded-for", "forwarded", "cf-connecting-ip"] {
+            assert_eq!(real_ip_of("198.51.100.1", header, &headers), "198.51.100.1");
+        }
+        assert_eq!(real_ip_of("10.0.0.1", "", &headers), "10.0.0.1");
+        assert_eq!(real_ip_of("10.0.0.1", "cf-connecting-ip", &headers), "203.0.113.9");
+    }
+
+    #[test]
+    fn forwarded_nodes_are_unquoted() {
+        let forwarded = [("forwarded", r#"for=198.51.100.7;proto=https, For="[::1]:80";by=10.0.0.1"#)];
+        assert_eq!(real_ip_of("10.0.0.1", "Forwarded", &forwarded), "::1");
+        let forwarded = [("forwarded", r#"for="[2001:db8::1]", for="192.0.2.1:8080""#)];
+        assert_eq!(real_ip_of("10.0.0.1", "forwarded", &forwarded), "2001:db8::1");
+        let forwarded = [("forwarded", "for=203.0.113.9, proto=http")];
+        assert_eq!(real_ip_of("10.0.0.1", "forwarded", &forwarded), "10.0.0.1");
+        let forwarded = [("forwarded", "for=unknown")];
+        assert_eq!(real_ip_of("10.0.0.1", "forwarded", &forwarded), "10.0.0.1");
+    }
+
+    fn header(req: &RequestHeader, name: &str) -> String {
+        let values: Vec<_> = req.headers.get_all(name).iter().map(|v| v.to_str().unwrap()).collect();
+        values.join(" | ")
+    }
+
+    fn hop(peer: &str) -> Hop {
+        Hop {
+            peer: Some(peer.parse().unwrap()),
+            client_ip: Some("203.0.113.9".parse().unwrap()),
+            tls: true,
+            host: "example.com".to_string(),
+            port: Some(443),
+        }
+    }
+
+    fn incoming() -> RequestHeader {
+        request(&[
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "spoofed.test"),
+            ("forwarded", "for=203.0.113.9"),
+            ("x-real-ip", "1.1.1.1"),
+        ])
+    }
+
+    #[test]
+    fn untrusted_chains_are_replaced() {
+        let mut req = incoming();
+        set_headers(&mut req, &hop("::1"), &trusted()).unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), "::1");
+        assert_eq!(header(&req, "x-forwarded-proto"), "https");
+        assert_eq!(header(&req, "x-forwarded-host"), "example.com");
+        assert_eq!(header(&req, "x-forwarded-port"), "443");
+        assert_eq!(header(&req, "x-real-ip"), "203.0.113.9");
+        assert_eq!(header(&req, "forwarded"), r#"for="[::1]";proto=https;host="example.com""#);
+    }
+
+    #[test]
+    fn trusted_chains_are_extended() {
+        let mut req = incoming();
+        set_headers(&mut req, &hop("10.0.0.1"), &trusted()).unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), "203.0.113.9, 10.0.0.1");
+        assert_eq!(header(&req, "x-forwarded-proto"), "http");
+        assert_eq!(header(&req, "x-forwarded-host"), "spoofed.test");
+        assert_eq!(header(&req, "x-forwarded-port"), "443");
+        assert_eq!(header(&req, "x-real-ip"), "203.0.113.9");
+        assert_eq!(header(&req, "forwarded"), r#"for=203.0.113.9, for=10.0.0.1;proto=https;host="example.com""#);
+    }
+}
@@ -0,0 +1,100 @@
+use std::net::IpAddr;
+
+use crate::util::IpNets;
+
+/// Allow and deny lists of a record, deny wins over allow.
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    /// When set, only these networks pass.
+    allow: Option<IpNets>,
+    deny: IpNets,
+}
+
+impl IpFilter {
+    /// `None` when neither list is set.
+    pub fn try_parse(allow: Option<Vec<String>>, deny: Option<Vec<String>>) -> Result<Option<Self>, String> {
+        let parse = |nets: Vec<String>| {
+            nets.iter()
+                .map(|n| IpNets::parse_net(n.trim()).map_err(|_| format!("Can't parse network: {n}")))
+                .collect::<Result<_, _>>()
+                .map(IpNets)
+        };
+        if allow.is_none() && deny.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { allow: allow.map(parse).transpose()?, deny: deny.map(parse).transpose()?.unwrap_or_default() }))
+    }
+
+    /// Rule rejecting the client. Clients without ip only pass records without allow list.
+    /// Ipv4 clients of dual-stack listeners are matched by their ipv4 address.
+    pub fn denied_by(&self, ip: Option<IpAddr>) -> Option<String> {
+        let ip = ip.map(|ip| ip.to_canonical());
+        if let Some(net) = ip.and_then(|ip| self.deny.find(&ip)) {
+            return Some(format!("deny {net}"));
+        }
+        match (&self.allow, ip) {
+            (Some(allow), Some(ip)) if allow.contains(&ip) => None,
+            (Some(_), _) => Some("allow list".to_string()),
+            (None, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: Option<&[&str]>, deny: Option<&[&str]>) -> IpFilter {
+        let list = |nets: &[&str]| nets.iter().map(|n| n.to_string()).collect();
+        IpFilter::try_parse(allow.map(list), deny.map(list)).unwrap().unwrap()
+    }
+
+    fn denied_by(filter: &IpFilter, ip: &str) -> Option<String> {
+        filter.denied_by(Some(ip.parse().unwrap()))
+    }
+
+    #[test]
+    fn parses_networks_and_single_ips() {
+        assert!(IpFilter::try_parse(None, None).unwrap().is_none());
+        assert!(IpFilter::try_parse(Some(vec![]), None).unwrap().is_some());
+        assert!(IpFilter::try_parse(Some(vec![" 10.0.0.0/8 ".to_string(), "2001:db8::1".to_string()]), None).is_ok());
+        assert_eq!(
+            IpFilter::try_parse(None, Some(vec!["10.0.0.0/33".to_string()])).unwrap_err(),
+            "Can't parse network: 10.0.0.0/33"
+        );
+        assert!(IpFilter::try_parse(Some(vec!["example.com".to_string()]), None).is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = filter(Some(&["10.0.0.0/8"]), Some(&["10.1.0.0/16", "10.2.0.1"]));
+        assert_eq!(denied_by(&filter, "10.3.0.1"), None);
+        assert_eq!(denied_by(&filter, "10.1.2.3").as_deref(), Some("deny 10.1.0.0/16"));
+        assert_eq!(denied_by(&filter, "10.2.0.1").as_deref(), Some("deny 10.2.0.1/32"));
+        assert_eq!(denied_by(&filter, "10.2.0.2"), None);
+        assert_eq!(denied_by(&filter, "192.0.2.1").as_deref(), Some("allow list"));
+    }
+
+    #[test]
+    fn deny_only_passes_others() {
+        let filter = filter(None, Some(&["192.0.2.0/24", "2001:db8::/32"]));
+        assert!(denied_by(&filter, "192.0.2.77").is_some());
+        assert!(denied_by(&filter, "2001:db8::5").is_some());
+        assert_eq!(denied_by(&filter, "198.51.100.1"), None);
+        assert_eq!(denied_by(&filter, "::1"), None);
+    }
+
+    #[test]
+    fn mapped_ipv6_matches_ipv4_rules() {
+        let filter = filter(Some(&["192.0.2.0/24"]), Some(&["192.0.2.9"]));
+        assert_eq!(denied_by(&filter, "::ffff:192.0.2.1"), None);
+        assert!(denied_by(&filter, "::ffff:192.0.2.9").is_some());
+        assert!(denied_by(&filter, "::ffff:198.51.100.1").is_some());
+    }
+
+    #[test]
+    fn unknown_peer_needs_no_allow_list() {
+        assert_eq!(filter(Some(&["0.0.0.0/0"]), None).denied_by(None).as_deref(), Some("allow list"));
+        assert_eq!(filter(None, Some(&["0.0.0.0/0", "::/0"])).denied_by(None), None);
+    }
+}
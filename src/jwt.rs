@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use http::{HeaderName, HeaderValue};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, AlgorithmFamily, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JwtRecord {
+    /// Shared secrets of HS256, HS384 and HS512 tokens.
+    pub secrets: Option<Vec<String>>,
+    /// JWKS with public (or `oct`) keys, read when config is loaded.
+    pub jwks_file: Option<PathBuf>,
+    /// Accepted `iss`, any if unset.
+    pub issuer: Option<Vec<String>>,
+    /// Accepted `aud`, tokens carrying `aud` are rejected if unset.
+    pub audience: Option<Vec<String>>,
+    /// Seconds of clock skew tolerated by `exp` and `nbf` checks, default 60.
+    pub leeway: Option<u64>,
+    /// Claims sent to upstream, claim name to header name.
+    pub claims: Option<HashMap<String, String>>,
+}
+
+struct Key {
+    kid: Option<String>,
+    key: DecodingKey,
+    /// Limited to the algorithm family of the key.
+    validation: Validation,
+}
+
+/// Keys and claim mapping of `jwt` of a record.
+pub struct Jwt {
+    keys: Vec<Key>,
+    pub claim_headers: Vec<(String, HeaderName)>,
+}
+
+impl std::fmt::Debug for Jwt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jwt")
+            .field("keys", &self.keys.iter().map(|k| (&k.kid, &k.validation.algorithms)).collect::<Vec<_>>())
+            .field("claim_headers", &self.claim_headers)
+            .finish()
+    }
+}
+
+/// Valid token.
+pub struct Claims {
+    /// `sub` claim, logged as user.
+    pub subject: Option<String>,
+    /// Mapped claims to send upstream.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Jwt {
+    /// Fails on secrets shorter than 32 bytes and on jwks keys of unsupported types.
+    pub fn try_parse(record: JwtRecord) -> Result<Self, String> {
+        let JwtRecord { secrets, jwks_file, issuer, audience, leeway, claims } = record;
+        let validation = |algorithms: Vec<Algorithm>| {
+            let mut v = Validation::new(algorithms[0]);
+            v.algorithms = algorithms;
+            v.leeway = leeway.unwrap_or(60);
+            v.validate_nbf = true;
+            let mut required = vec!["exp"];
+            if let Some(issuer) = &issuer {
+                v.set_issuer(issuer);
+                required.push("iss");
+            }
+            // without audience `aud` stays validated, so tokens meant for other services fail
+            if let Some(audience) = &audience {
+                v.set_audience(audience);
+                required.push("aud");
+            }
+            v.set_required_spec_claims(&required);
+            v
+        };
+        let family = |family: AlgorithmFamily| family.algorithms().to_vec();
+
+        let mut keys = Vec::new();
+        for secret in secrets.unwrap_or_default() {
+            if secret.len() < 32 {
+                return Err("Jwt secrets must be at least 32 bytes".to_string());
+            }
+            let validation = validation(family(AlgorithmFamily::Hmac));
+            keys.push(Key { kid: None, key: DecodingKey::from_secret(secret.as_bytes()), validation });
+        }
+        if let Some(path) = jwks_file {
+            let content = std::fs::read_to_string(&path).map_err(|e| format!("Can't read jwks_file {}: {e}", path.display()))?;
+            let jwks: JwkSet = serde_json::from_str(&content).map_err(|e| format!("Can't parse jwks_file {}: {e}", path.display()))?;
+            for jwk in jwks.keys {
+                let kid = jwk.common.key_id.clone();
+                let name = kid.as_deref().unwrap_or("without kid");
+                let key = DecodingKey::from_jwk(&jwk).map_err(|e| format!("Can't use jwk {name}: {e}"))?;
+                let algorithms = match (jwk.common.key_algorithm, &jwk.algorithm) {
+                    (Some(alg), _) => {
+                        vec![Algorithm::from_str(&alg.to_string()).map_err(|_| format!("Jwk {name} has unsupported alg {alg}"))?]
+                    }
+                    (None, AlgorithmParameters::RSA(_)) => family(AlgorithmFamily::Rsa),
+                    (None, AlgorithmParameters::EllipticCurve(_)) => family(AlgorithmFamily::Ec),
+                    (None, AlgorithmParameters::OctetKeyPair(_)) => family(AlgorithmFamily::Ed),
+                    (None, AlgorithmParameters::OctetKey(_)) => family(AlgorithmFamily::Hmac),
+                    (None, _) => return Err(format!("Jwk {name} has unsupported key type")),
+                };
+                keys.push(Key { kid, key, validation: validation(algorithms) });
+            }
+        }
+        if keys.is_empty() {
+            return Err("Jwt has no keys, set secrets or jwks_file".to_string());
+        }
+        let mut claim_headers = claims
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(claim, header)| match header.parse() {
+                Ok(header) => Ok((claim, header)),
+                Err(_) => Err(format!("Invalid header name for claim {claim}: {header}")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        claim_headers.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Self { keys, claim_headers })
+    }
+
+    /// Validates bearer token of `Authorization` header, error tells why it was rejected.
+    pub fn validate(&self, authorization: Option<&HeaderValue>) -> Result<Claims, String> {
+        let authorization = authorization.ok_or("no token")?.to_str().map_err(|_| "malformed authorization")?;
+        let token = match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ => return Err("no bearer token".to_string()),
+        };
+        let header = jsonwebtoken::decode_header(token).map_err(|e| format!("malformed token: {e}"))?;
+        let keys = self.keys.iter().filter(|k| {
+            k.validation.algorithms.contains(&header.alg)
+                && (header.kid.is_none() || k.kid.is_none() || k.kid == header.kid)
+        });
+        let mut claims = None;
+        for key in keys {
+            match jsonwebtoken::decode::<Map<String, Value>>(token, &key.key, &key.validation) {
+                Ok(data) => {
+                    claims = Some(data.claims);
+                    break;
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm) => continue,
+                Err(e) => return Err(format!("invalid token: {e}")),
+            }
+        }
+        let claims = claims.ok_or_else(|| format!("no key verifies {:?} token", header.alg))?;
+
+        let value = |v: &Value| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        let headers = self
+            .claim_headers
+            .iter()
+            .filter_map(|(claim, header)| Some((header.clone(), HeaderValue::from_str(&value(claims.get(claim)?)).ok()?)))
+            .collect();
+        Ok(Claims { subject: claims.get("sub").map(value), headers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+    /// Modulus of a 2048 bit test RSA key, base64url.
+    const RSA_N: &str = "8p_G8P1iHKl4yuvsd2RMh7tfbgi_Ikdh5npeq9p0KJh7wzo_AI0nxQot_yWazepmJDmT3xlcZ87G9_iWJnYVhFzU-4Oq39m_QmxjKoON6X-bsPCS95Sz06kkEvBSU5hdOfeUUqu_nlYUoDB-dIoFPD4lJ4slxHD-r6exZJ6qooD7GbmPkHMxllgEs36kPB0NgKm9WfxMpfJMpB4jRa-wxIMcXR1TUmrd0amqHVEcMXr6YZYengen22q9ckLo9BCCZvd_AMXP_pG5fVLxEbpyGvPOTN_PDnKtttvfii72KCIPgO0jJ4QaKNxBAuYW-gGxjqVPs8s1zEH8DiuMIEmxyw";
+
+    fn record() -> JwtRecord {
+        JwtRecord {
+            secrets: Some(vec![SECRET.to_string()]),
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            leeway: Some(0),
+            claims: None,
+        }
+    }
+
+    fn jwks(keys: Value) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("simple-proxy-jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, json!({ "keys": keys }).to_string()).unwrap();
+        path
+    }
+
+    fn now() -> i64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn token(header: Header, secret: &[u8], claims: Value) -> HeaderValue {
+        let token = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap();
+        HeaderValue::from_str(&format!("Bearer {token}")).unwrap()
+    }
+
+    fn hs256(claims: Value) -> HeaderValue {
+        token(Header::new(Algorithm::HS256), SECRET.as_bytes(), claims)
+    }
+
+    #[test]
+    fn hs256_token_is_accepted() {
+        let mut record = record();
+        record.claims = Some(HashMap::from([("role".to_string(), "X-Role".to_string())]));
+        let jwt = Jwt::try_parse(record).unwrap();
+        let claims = jwt.validate(Some(&hs256(json!({"sub": "alice", "role": "admin", "exp": now() + 60})))).unwrap();
+        assert_eq!(claims.subject.as_deref(), Some("alice"));
+        assert_eq!(claims.headers, vec![(HeaderName::from_static("x-role"), HeaderValue::from_static("admin"))]);
+
+        assert_eq!(jwt.validate(None).err().unwrap(), "no token");
+        assert_eq!(jwt.validate(Some(&HeaderValue::from_static("Basic YTpi"))).err().unwrap(), "no bearer token");
+        assert!(jwt.validate(Some(&HeaderValue::from_static("Bearer x.y.z"))).err().unwrap().starts_with("malformed token"));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let jwt = Jwt::try_parse(record()).unwrap();
+        let other = token(Header::new(Algorithm::HS256), b"fedcba9876543210fedcba9876543210", json!({"exp": now() + 60}));
+        assert_eq!(jwt.validate(Some(&other)).err().unwrap(), "no key verifies HS256 token");
+    }
+
+    #[test]
+    fn expired_and_not_yet_valid_tokens_are_rejected() {
+        let jwt = Jwt::try_parse(record()).unwrap();
+        let err = jwt.validate(Some(&hs256(json!({"exp": now() - 10})))).err().unwrap();
+        assert!(err.contains("ExpiredSignature"), "{err}");
+        let err = jwt.validate(Some(&hs256(json!({"exp": now() + 60, "nbf": now() + 30})))).err().unwrap();
+        assert!(err.contains("ImmatureSignature"), "{err}");
+        let err = jwt.validate(Some(&hs256(json!({"sub": "alice"})))).err().unwrap();
+        assert!(err.contains("exp"), "{err}");
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60, "nbf": now() - 10})))).is_ok());
+    }
+
+    #[test]
+    fn issuer_must_match() {
+        let mut record = record();
+        record.issuer = Some(vec!["https://id.test".to_string()]);
+        let jwt = Jwt::try_parse(record).unwrap();
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60, "iss": "https://id.test"})))).is_ok());
+        let err = jwt.validate(Some(&hs256(json!({"exp": now() + 60, "iss": "https://evil.test"})))).err().unwrap();
+        assert!(err.contains("InvalidIssuer"), "{err}");
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60})))).is_err(), "iss is required");
+    }
+
+    #[test]
+    fn audience_must_match() {
+        let mut record = record();
+        record.audience = Some(vec!["app".to_string()]);
+        let jwt = Jwt::try_parse(record).unwrap();
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60, "aud": "app"})))).is_ok());
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60, "aud": ["other", "app"]})))).is_ok());
+        let err = jwt.validate(Some(&hs256(json!({"exp": now() + 60, "aud": "other"})))).err().unwrap();
+        assert!(err.contains("InvalidAudience"), "{err}");
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60})))).is_err(), "aud is required");
+    }
+
+    #[test]
+    fn audience_is_rejected_when_not_configured() {
+        let jwt = Jwt::try_parse(record()).unwrap();
+        assert!(jwt.validate(Some(&hs256(json!({"exp": now() + 60})))).is_ok());
+        for aud in [json!("other"), json!(["other"])] {
+            let err = jwt.validate(Some(&hs256(json!({"exp": now() + 60, "aud": aud})))).err().unwrap();
+            assert!(err.contains("InvalidAudience"), "{err}");
+        }
+    }
+
+    #[test]
+    fn alg_none_and_algorithm_confusion_are_rejected() {
+        let b64 = |v: Value| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(v.to_string());
+        let unsigned = format!("Bearer {}.{}.", b64(json!({"alg": "none", "typ": "JWT"})), b64(json!({"exp": now() + 60})));
+        let jwt = Jwt::try_parse(record()).unwrap();
+        assert!(jwt.validate(Some(&HeaderValue::from_str(&unsigned).unwrap())).err().unwrap().starts_with("malformed token"));
+
+        // HS256 token signed with the public RSA key as secret
+        let path = jwks(json!([{ "kty": "RSA", "kid": "rsa", "n": RSA_N, "e": "AQAB" }]));
+        let mut record = record();
+        record.secrets = None;
+        record.jwks_file = Some(path.clone());
+        let jwt = Jwt::try_parse(record).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rsa".to_string());
+        let forged = token(header, RSA_N.as_bytes(), json!({"exp": now() + 60}));
+        assert_eq!(jwt.validate(Some(&forged)).err().unwrap(), "no key verifies HS256 token");
+    }
+
+    #[test]
+    fn keys_are_filtered_by_kid() {
+        let k = |secret: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret);
+        let (a, b) = ("a".repeat(32), "b".repeat(32));
+        let path = jwks(json!([
+            { "kty": "oct", "kid": "a", "k": k(&a) },
+            { "kty": "oct", "kid": "b", "alg": "HS512", "k": k(&b) },
+        ]));
+        let mut record = record();
+        record.secrets = None;
+        record.jwks_file = Some(path.clone());
+        let jwt = Jwt::try_parse(record).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let with_kid = |alg: Algorithm, kid: Option<&str>, secret: &str| {
+            let mut header = Header::new(alg);
+            header.kid = kid.map(String::from);
+            token(header, secret.as_bytes(), json!({"exp": now() + 60}))
+        };
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS256, Some("a"), &a))).is_ok());
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS256, None, &a))).is_ok(), "without kid every key is tried");
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS512, Some("b"), &b))).is_ok());
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS256, Some("b"), &a))).is_err(), "kid b doesn't select key a");
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS256, Some("b"), &b))).is_err(), "key b only accepts HS512");
+        assert!(jwt.validate(Some(&with_kid(Algorithm::HS256, Some("c"), &a))).is_err());
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        let mut short = record();
+        short.secrets = Some(vec!["short".to_string()]);
+        assert!(Jwt::try_parse(short).unwrap_err().contains("at least 32 bytes"));
+        let mut none = record();
+        none.secrets = None;
+        assert!(Jwt::try_parse(none).unwrap_err().contains("no keys"));
+        let mut header = record();
+        header.claims = Some(HashMap::from([("sub".to_string(), "bad header".to_string())]));
+        assert!(Jwt::try_parse(header).unwrap_err().contains("Invalid header name"));
+    }
+}
@@ -1,16 +1,47 @@
 use pingora::{ prelude::*, server::configuration::ServerConf, server::Server};
 use pingora::listeners::tls::TlsSettings;
+use pingora::apps::http_app::HttpServer;
+use pingora::listeners::PreTlsCallback;
+use pingora::proxy::http_proxy;
+use pingora::services::listening::Service;
+use instant_acme::ChallengeType;
 use rustls::crypto::ring::default_provider;
 use rustls::crypto::CryptoProvider;
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use tracing::{info, warn};
 
-use crate::config::{ConfigRecord, RouteConfig};
-use crate::services::http::HttpGateway;
-use crate::services::dir::dirs_router;
+use crate::access_log::{AccessLogFormat, AccessLogOutput, RotatingFile, access_log};
+use crate::certs::CertResolver;
+use crate::client_cert::ClientCertCallbacks;
+use crate::config::{ConfigRecord, ListenerParsedRecord, RouteConfig, SharedConfig};
+use crate::proxy_protocol::{ProxyProtocolApp, ProxyProtocolPreTls};
+use crate::services::acme::{AcmeChallenges, AcmeHttp, AcmeService, AcmeTlsAlpn};
+use crate::services::http::{HttpGateway, HttpsRedirect};
+use crate::services::dir::DirServers;
+use crate::services::reload::ConfigReloader;
+use crate::services::tcp::TcpGateway;
+use crate::services::upstreams::UpstreamUpdater;
+use crate::util::IpNets;
 
+mod access_log;
+mod balancer;
+mod basic_auth;
+mod certs;
+mod client_cert;
 mod config;
+mod discovery;
+mod forward_auth;
+mod forwarded;
+mod ip_filter;
+mod jwt;
+mod metrics;
+mod proxy_protocol;
+mod rate_limit;
 mod services;
+#[cfg(test)]
+mod test_util;
 mod util;
 
 
@@ -21,7 +52,27 @@ env_config!(
         LISTEN_ADDR: String = "0.0.0.0:443".to_string(),
         HTTPS : bool = true,
         GRACE_PERIOD: u64 = u64::MAX,
-        GRACEFUL_SHUTDOWN_TIMEOUT: u64 = u64::MAX
+        GRACEFUL_SHUTDOWN_TIMEOUT: u64 = u64::MAX,
+        CONFIG_WATCH_INTERVAL: u64 = 5,
+        STRICT_CONFIG: bool = false,
+        DNS_REFRESH_INTERVAL: u64 = 0,
+        ACME: bool = false,
+        ACME_DIRECTORY: String = "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+        ACME_EMAIL: String = "".to_string(),
+        ACME_CA_ROOT: String = "".to_string(),
+        ACME_CHALLENGE: String = "http-01".to_string(),
+        ACME_HTTP_ADDR: String = "0.0.0.0:80".to_string(),
+        HTTP_REDIRECT_ADDR: String = "".to_string(),
+        METRICS_ADDR: String = "".to_string(),
+        ACCESS_LOG: String = "".to_string(),
+        ACCESS_LOG_FORMAT: String = "combined".to_string(),
+        ACCESS_LOG_MAX_SIZE: u64 = 0,
+        ACCESS_LOG_ROTATE_INTERVAL: u64 = 0,
+        ACCESS_LOG_KEEP: usize = 0,
+        REQUEST_ID_HEADER: http::HeaderName = http::HeaderName::from_static("x-request-id"),
+        REQUEST_ID_TRUSTED: IpNets = IpNets::default(),
+        TRUSTED_PROXIES: IpNets = IpNets::default(),
+        REAL_IP_HEADER: String = "".to_string()
     }
 );
 
@@ -30,6 +81,12 @@ fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     CryptoProvider::install_default(default_provider()).ok();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("check") {
+        let path = args.get(2).cloned().unwrap_or_else(|| CFG.CONFIG_PATH.clone());
+        std::process::exit(check_config(&path)?);
+    }
+
     let mut server = Server::new_with_opt_and_conf(None, ServerConf{
         grace_period_seconds: Some(CFG.GRACE_PERIOD),
         graceful_shutdown_timeout_seconds: Some(CFG.GRACEFUL_SHUTDOWN_TIMEOUT),
@@ -42,47 +99,208 @@ fn main() -> anyhow::Result<()> {
         .enable_all()
         .build()?;
 
-    let config = runtime.block_on(async {
-        let config = load_config(&CFG.CONFIG_PATH).await.expect("Can't load config");
-        let shared_config: Arc<RouteConfig> = Arc::new(config.clone());
-        for (host, dirs) in config.dir.listen.into_iter() {
-            let r = dirs_router(dirs);
-            tokio::spawn(async move {
-                let l = tokio::net::TcpListener::bind(host).await;
-                let Ok(listener) = l else {
-                    warn!("Can't bind port for dir server: {}", l.unwrap_err());
-                    return;
-                };
-                info!("Starting dir server on {}", host);
-                if let Err(e) = axum::serve(listener, r).await 
-                {
-                    tracing::error!("Dir server on {} failed: {}", host, e);
-                }
-            });
+    let dirs = Arc::new(DirServers::new(runtime.handle().clone()));
+    let config: SharedConfig = runtime.block_on(async {
+        let config = load_config(&CFG.CONFIG_PATH, CFG.STRICT_CONFIG, None).await?;
+        dirs.apply(&config.dir.listen);
+        anyhow::Ok(Arc::new(ArcSwap::from_pointee(config)))
+    })?;
+
+    let access_log = match CFG.ACCESS_LOG.as_str() {
+        "" => None,
+        target => {
+            let output = if target == "stdout" {
+                AccessLogOutput::Stdout
+            } else {
+                AccessLogOutput::File(RotatingFile {
+                    path: target.into(),
+                    max_size: (CFG.ACCESS_LOG_MAX_SIZE > 0).then_some(CFG.ACCESS_LOG_MAX_SIZE),
+                    interval: (CFG.ACCESS_LOG_ROTATE_INTERVAL > 0).then(|| Duration::from_secs(CFG.ACCESS_LOG_ROTATE_INTERVAL)),
+                    keep: CFG.ACCESS_LOG_KEEP,
+                })
+            };
+            let format = AccessLogFormat::parse(&CFG.ACCESS_LOG_FORMAT).map_err(anyhow::Error::msg)?;
+            let (access_log, writer) = access_log(format, output);
+            server.add_service(background_service("Access log", writer));
+            info!("Access log is written to {target}");
+            Some(Arc::new(access_log))
         }
-        shared_config
-    });
+    };
+
+    let listeners = match config.load().listener.clone() {
+        listeners if listeners.is_empty() => vec![ListenerParsedRecord::from_env()?],
+        listeners => listeners,
+    };
+    let https = listeners.iter().any(|l| l.tls);
+    // one resolver per cert dir, shared between listeners and acme
+    let mut certs: HashMap<String, Arc<CertResolver>> = HashMap::new();
+    for listener in listeners.iter().filter(|l| l.tls) {
+        certs.entry(listener.cert_path.clone()).or_insert_with(|| Arc::new(CertResolver::load(&listener.cert_path)));
+    }
 
-    let mut proxy = http_proxy_service(&server.configuration, HttpGateway{config: Arc::clone(&config)});
-    let cert_path = format!("{}/fullchain.pem", CFG.CERT_PATH);
-    let key_path = format!("{}/privkey.pem", CFG.CERT_PATH);
+    let acme_challenge = match CFG.ACME_CHALLENGE.as_str() {
+        "http-01" => ChallengeType::Http01,
+        "tls-alpn-01" => ChallengeType::TlsAlpn01,
+        other => anyhow::bail!("Unknown ACME_CHALLENGE {other}, expected http-01 or tls-alpn-01"),
+    };
+    if CFG.ACME && !https {
+        warn!("ACME is ignored without tls listeners");
+    }
+    if !CFG.HTTP_REDIRECT_ADDR.is_empty() && !https {
+        warn!("HTTP_REDIRECT_ADDR is ignored without tls listeners");
+    }
+    let redirect_listener = https && !CFG.HTTP_REDIRECT_ADDR.is_empty();
+    let acme_certs = (CFG.ACME && https)
+        .then(|| Arc::clone(certs.entry(CFG.CERT_PATH.clone()).or_insert_with(|| Arc::new(CertResolver::load(&CFG.CERT_PATH)))));
+    let acme_challenges = Arc::new(AcmeChallenges::default());
 
-    if CFG.HTTPS {
-        if PathBuf::from(&cert_path).exists() && PathBuf::from(&key_path).exists() {
-            let tls = TlsSettings::intermediate(&cert_path, &key_path)?;
-            proxy.add_tls_with_settings(&CFG.LISTEN_ADDR, None, tls);
+    for listener in listeners.iter() {
+        let addr = listener.addr.to_string();
+        let gateway = HttpGateway{config: Arc::clone(&config), listener: Some(listener.addr), redirect: None, access_log: access_log.clone()};
+        // tls listeners read PROXY header in pre tls callback
+        let app = ProxyProtocolApp {
+            inner: Arc::new(http_proxy(&server.configuration, gateway)),
+            enabled: listener.proxy_protocol && !listener.tls,
+        };
+        let mut proxy = Service::new(format!("Proxy {addr}"), app);
+        if listener.tls {
+            let resolver = &certs[&listener.cert_path];
+            if resolver.is_empty() && !CFG.ACME {
+                warn!("Can't find any cert and key in {}, skipping listener {addr}", listener.cert_path);
+                continue;
+            }
+            let mut tls = match &listener.client_ca {
+                Some(ca) => {
+                    let verifier = client_cert::verifier(Path::new(ca), listener.client_auth)
+                        .with_context(|| format!("Can't load client_ca of listener {addr}"))?;
+                    let mut tls = TlsSettings::with_callbacks(Box::new(ClientCertCallbacks))?;
+                    tls.set_client_cert_verifier(verifier);
+                    tls
+                }
+                None => TlsSettings::intermediate("", "")?,
+            };
+            tls.set_cert_resolver(Arc::clone(resolver) as _);
+            tls.enable_h2();
+            let mut pre_tls: Option<PreTlsCallback> = (CFG.ACME && acme_challenge == ChallengeType::TlsAlpn01)
+                .then(|| Arc::new(AcmeTlsAlpn { challenges: Arc::clone(&acme_challenges) }) as _);
+            if listener.proxy_protocol {
+                pre_tls = Some(Arc::new(ProxyProtocolPreTls { next: pre_tls }));
+            }
+            if let Some(pre_tls) = pre_tls {
+                proxy.endpoints().set_pre_tls_callback(pre_tls);
+            }
+            proxy.add_tls_with_settings(&addr, None, tls);
         } else {
-            warn!("Can't find cert or key");
+            proxy.add_tcp(&addr);
         }
-    } else {
-        proxy.add_tcp(&CFG.LISTEN_ADDR);
+        info!(
+            "Proxy listening on {addr} {} tls encryption{}",
+            if listener.tls { "with" } else { "without" },
+            if listener.proxy_protocol { ", expecting PROXY header" } else { "" }
+        );
+        server.add_service(proxy);
+    }
+
+    if redirect_listener {
+        let https_port = listeners.iter().find(|l| l.tls).map_or(443, |l| l.addr.port());
+        let redirect = HttpsRedirect {
+            https_port,
+            acme: (CFG.ACME && acme_challenge == ChallengeType::Http01).then(|| Arc::clone(&acme_challenges)),
+        };
+        let mut http = http_proxy_service(&server.configuration, HttpGateway{config: Arc::clone(&config), listener: None, redirect: Some(redirect), access_log: access_log.clone()});
+        http.add_tcp(&CFG.HTTP_REDIRECT_ADDR);
+        info!("Plain http listening on {}, redirecting to https", CFG.HTTP_REDIRECT_ADDR);
+        server.add_service(http);
+    }
+
+    for port in config.load().tcp.keys() {
+        let addr = format!("0.0.0.0:{port}");
+        let mut tcp = Service::new(format!("Tcp proxy {port}"), TcpGateway { port: *port, config: Arc::clone(&config) });
+        tcp.add_tcp(&addr);
+        info!("Tcp proxy listening on {addr}");
+        server.add_service(tcp);
+    }
+
+    if let Some(certs) = acme_certs {
+        // redirect listener answers challenges itself
+        if acme_challenge == ChallengeType::Http01 && !redirect_listener {
+            let mut http = Service::new("Acme http-01".to_string(), HttpServer::new_app(AcmeHttp { challenges: Arc::clone(&acme_challenges) }));
+            http.add_tcp(&CFG.ACME_HTTP_ADDR);
+            info!("Acme http-01 challenges are answered on {}", CFG.ACME_HTTP_ADDR);
+            server.add_service(http);
+        }
+        let acme = AcmeService {
+            config: Arc::clone(&config),
+            certs,
+            challenges: acme_challenges,
+            cert_path: CFG.CERT_PATH.clone().into(),
+            directory: CFG.ACME_DIRECTORY.clone(),
+            email: CFG.ACME_EMAIL.clone(),
+            ca_root: (!CFG.ACME_CA_ROOT.is_empty()).then(|| CFG.ACME_CA_ROOT.clone()),
+            challenge: acme_challenge,
+        };
+        server.add_service(background_service("Acme", acme));
+    }
+
+    if !CFG.METRICS_ADDR.is_empty() {
+        let mut metrics = pingora_prometheus::prometheus_http_service();
+        metrics.add_tcp(&CFG.METRICS_ADDR);
+        info!("Metrics listening on {}", CFG.METRICS_ADDR);
+        server.add_service(metrics);
     }
-    info!("Proxy listening on {} {} tls encryption", CFG.LISTEN_ADDR, if CFG.HTTPS { "with" } else { "without" });
 
-    server.add_service(proxy);
+    let reloader = ConfigReloader {
+        path: CFG.CONFIG_PATH.clone(),
+        config: Arc::clone(&config),
+        dirs,
+        certs: certs.into_values().collect(),
+        interval: (CFG.CONFIG_WATCH_INTERVAL > 0).then(|| Duration::from_secs(CFG.CONFIG_WATCH_INTERVAL)),
+    };
+    server.add_service(background_service("Config reload", reloader));
+    server.add_service(background_service("Upstream updates", UpstreamUpdater { config: Arc::clone(&config) }));
+
     server.run_forever();
 }
 
-async fn load_config(path: &str) -> anyhow::Result<RouteConfig> {
-    Ok(ConfigRecord::from_file(path)?.to_route_config().await)
+/// On startup records with problems are skipped unless `STRICT_CONFIG` is set,
+/// on reload (`strict`) any problem rejects the whole config. Balancers of
+/// `previous` config are reused where upstreams didn't change.
+async fn load_config(path: &str, strict: bool, previous: Option<&RouteConfig>) -> anyhow::Result<RouteConfig> {
+    let (config, issues) = ConfigRecord::from_file(path)?.to_route_config(previous).await;
+    if strict && !issues.is_empty() {
+        for issue in issues.iter() {
+            warn!("{path}:{issue}");
+        }
+        anyhow::bail!("{} problem(s) found in {path}", issues.len());
+    }
+    for issue in issues.iter() {
+        warn!("{path}:{issue}{}", if issue.skipped { ", skipping" } else { "" });
+    }
+    Ok(config)
+}
+
+/// `check` subcommand: loads config the same way as on startup and prints
+/// every problem found. Returns process exit code.
+fn check_config(path: &str) -> anyhow::Result<i32> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let record = match ConfigRecord::from_file(path) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{path}: {e}");
+            return Ok(1);
+        }
+    };
+    let (_, issues) = runtime.block_on(record.to_route_config(None));
+    for issue in issues.iter() {
+        println!("{path}:{issue}");
+    }
+    if issues.is_empty() {
+        println!("{path}: ok");
+        Ok(0)
+    } else {
+        println!("{path}: {} problem(s) found", issues.len());
+        Ok(1)
+    }
 }
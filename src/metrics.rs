@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use pingora_prometheus::prometheus::{
+    HistogramVec, IntCounterVec, IntGaugeVec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+};
+
+/// Domain label for hosts that don't match any record, keeps label cardinality bounded.
+pub const UNKNOWN_DOMAIN: &str = "unknown";
+
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_http_requests_total",
+        "Http requests handled by the gateway",
+        &["domain", "record", "upstream", "status"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proxy_http_request_duration_seconds",
+        "Time from reading request to finishing response",
+        &["domain", "record", "upstream", "status"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_IN_FLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("proxy_http_requests_in_flight", "Http requests being handled", &["domain"]).unwrap()
+});
+
+pub static UPSTREAM_CONNECT_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_upstream_connect_errors_total",
+        "Failed connections to upstreams",
+        &["protocol", "upstream"]
+    )
+    .unwrap()
+});
+
+pub static TCP_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_tcp_bytes_total",
+        "Bytes proxied by tcp gateway, counted when connection closes",
+        &["port", "upstream", "direction"]
+    )
+    .unwrap()
+});
+
+pub static DIR_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("proxy_dir_hits_total", "Requests routed to dir servers", &["domain", "route"]).unwrap()
+});
+
+pub static HTTP_UPGRADED_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proxy_http_upgraded_duration_seconds",
+        "Lifetime of upgraded (e.g. WebSocket) connections, observed when they close",
+        &["domain", "record", "upstream"],
+        vec![1.0, 10.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 86400.0]
+    )
+    .unwrap()
+});
+
+pub static HTTP_UPGRADED_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_http_upgraded_bytes_total",
+        "Bytes proxied over upgraded connections, counted when connection closes",
+        &["domain", "record", "upstream", "direction"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_http_rate_limited_total",
+        "Requests rejected with 429 by rate limit of their record",
+        &["domain", "record"]
+    )
+    .unwrap()
+});
+
+/// Labels of a single http request, filled in while it is routed.
+pub struct HttpRequestMetrics {
+    start: Instant,
+    /// `None` until request is counted as in flight.
+    domain: Option<String>,
+    pub record: String,
+    pub upstream: String,
+}
+
+impl Default for HttpRequestMetrics {
+    fn default() -> Self {
+        Self { start: Instant::now(), domain: None, record: String::new(), upstream: String::new() }
+    }
+}
+
+impl HttpRequestMetrics {
+    /// Time since the request was read.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Counts request as in flight, `domain` is `None` for unknown hosts.
+    pub fn started(&mut self, domain: Option<&str>) {
+        if self.domain.is_some() {
+            return;
+        }
+        let domain = domain.unwrap_or(UNKNOWN_DOMAIN).to_string();
+        HTTP_IN_FLIGHT.with_label_values(&[&domain]).inc();
+        self.domain = Some(domain);
+    }
+
+    pub fn finished(&self, status: u16) {
+        let domain = self.domain.as_deref().unwrap_or(UNKNOWN_DOMAIN);
+        if self.domain.is_some() {
+            HTTP_IN_FLIGHT.with_label_values(&[domain]).dec();
+        }
+        let status = status.to_string();
+        let labels = [domain, &self.record, &self.upstream, &status];
+        HTTP_REQUESTS.with_label_values(&labels).inc();
+        HTTP_REQUEST_DURATION.with_label_values(&labels).observe(self.start.elapsed().as_secs_f64());
+    }
+
+    /// Request rejected by rate limit of `record`.
+    pub fn rate_limited(&self) {
+        let domain = self.domain.as_deref().unwrap_or(UNKNOWN_DOMAIN);
+        HTTP_RATE_LIMITED.with_label_values(&[domain, &self.record]).inc();
+    }
+
+    /// Upgraded connection closed, `sent` is counted towards upstream and `received` from it.
+    pub fn upgraded(&self, duration: Duration, sent: u64, received: u64) {
+        let domain = self.domain.as_deref().unwrap_or(UNKNOWN_DOMAIN);
+        let labels = [domain, &self.record, &self.upstream];
+        HTTP_UPGRADED_DURATION.with_label_values(&labels).observe(duration.as_secs_f64());
+        HTTP_UPGRADED_BYTES.with_label_values(&[domain, &self.record, &self.upstream, "sent"]).inc_by(sent);
+        HTTP_UPGRADED_BYTES.with_label_values(&[domain, &self.record, &self.upstream, "received"]).inc_by(received);
+    }
+}
@@ -0,0 +1,388 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::apps::ServerApp;
+use pingora::connectors::L4Connect;
+use pingora::listeners::{PreTlsCallback, PreTlsProcess};
+use pingora::protocols::SocketDigest;
+use pingora::protocols::l4::socket::SocketAddr as L4SocketAddr;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::{GetSocketDigest, Stream, UniqueID};
+use pingora::server::ShutdownWatch;
+use pingora::{Error, ErrorType, OrErr};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header including CRLF.
+const V1_MAX_LEN: usize = 107;
+/// Longest accepted v2 address block with TLVs, the format itself allows 64K.
+const V2_MAX_LEN: usize = 4096;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Addresses from PROXY header, `None` for LOCAL and UNKNOWN connections
+/// (e.g. load balancer health checks) which keep socket addresses.
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads PROXY v1 or v2 header, nothing past it is consumed.
+pub async fn read_header<S: AsyncRead + Unpin + ?Sized>(stream: &mut S) -> std::io::Result<ProxyHeader> {
+    // shorter than any complete header, so it never reaches payload
+    let mut start = [0u8; 8];
+    stream.read_exact(&mut start).await?;
+    if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line[..line.len() - 2]);
+    }
+    if start != V2_SIGNATURE[..8] {
+        return Err(invalid("PROXY header is missing"));
+    }
+    let mut rest = [0u8; 8];
+    stream.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] || rest[4] >> 4 != 2 {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    if len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header is too long"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    let (source, destination) = match (rest[4] & 0x0f, rest[5] >> 4) {
+        // LOCAL
+        (0, _) => (None, None),
+        // PROXY over AF_INET
+        (1, 1) if payload.len() >= 12 => {
+            let ip = |i: usize| IpAddr::from([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]]);
+            let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+            (Some(SocketAddr::new(ip(0), port(8))), Some(SocketAddr::new(ip(4), port(10))))
+        }
+        // PROXY over AF_INET6
+        (1, 2) if payload.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&payload[i..i + 16]);
+                IpAddr::from(octets)
+            };
+            let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+            (Some(SocketAddr::new(ip(0), port(32))), Some(SocketAddr::new(ip(16), port(34))))
+        }
+        (1, 1 | 2) => return Err(invalid("PROXY v2 address block is truncated")),
+        // AF_UNSPEC and AF_UNIX keep socket addresses
+        (1, _) => (None, None),
+        _ => return Err(invalid("invalid PROXY v2 command")),
+    };
+    Ok(ProxyHeader { source, destination })
+}
+
+fn parse_v1(line: &[u8]) -> std::io::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ascii"))?;
+    let parts: Vec<_> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader { source: None, destination: None }),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let addr = |ip: &str, port: &str| -> std::io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("invalid ip in PROXY v1 header"))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid("ip family doesn't match PROXY v1 protocol"));
+                }
+                let port = port.parse().map_err(|_| invalid("invalid port in PROXY v1 header"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(ProxyHeader { source: Some(addr(src, sport)?), destination: Some(addr(dst, dport)?) })
+        }
+        _ => Err(invalid("invalid PROXY v1 header")),
+    }
+}
+
+/// Both addresses have to be of the same family, ipv4 is mapped when they differ.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (v6(source), v6(destination))
+    }
+}
+
+pub fn encode(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = same_family(source, destination);
+    match version {
+        ProxyProtocolVersion::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            header.push(0x21);
+            let mut addrs = Vec::new();
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    header.push(0x11);
+                    addrs.extend(src.octets());
+                    addrs.extend(dst.octets());
+                }
+                (src, dst) => {
+                    header.push(0x21);
+                    let v6 = |ip: IpAddr| match ip {
+                        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                        IpAddr::V6(ip) => ip.octets(),
+                    };
+                    addrs.extend(v6(src));
+                    addrs.extend(v6(dst));
+                }
+            }
+            addrs.extend(source.port().to_be_bytes());
+            addrs.extend(destination.port().to_be_bytes());
+            header.extend((addrs.len() as u16).to_be_bytes());
+            header.extend(addrs);
+            header
+        }
+    }
+}
+
+/// Reads PROXY header and makes its source the peer address of the connection.
+async fn accept<S>(stream: &mut S) -> std::io::Result<()>
+where
+    S: AsyncRead + GetSocketDigest + UniqueID + Unpin + ?Sized,
+{
+    let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout while reading PROXY header"))??;
+    let Some(source) = header.source else {
+        return Ok(());
+    };
+    let local = stream.get_socket_digest().and_then(|d| d.local_addr().cloned());
+    #[cfg(unix)]
+    let digest = SocketDigest::from_raw_fd(stream.id());
+    #[cfg(windows)]
+    let digest = SocketDigest::from_raw_socket(stream.id() as _);
+    let _ = digest.peer_addr.set(Some(L4SocketAddr::Inet(source)));
+    let _ = digest.local_addr.set(local);
+    stream.set_socket_digest(digest);
+    Ok(())
+}
+
+/// Accepts PROXY header on tls listeners, `next` runs afterwards.
+pub struct ProxyProtocolPreTls {
+    pub next: Option<PreTlsCallback>,
+}
+
+#[async_trait]
+impl PreTlsProcess for ProxyProtocolPreTls {
+    async fn process(&self, stream: &mut L4Stream) -> pingora::Result<()> {
+        accept(stream).await.or_err(ErrorType::AcceptError, "Can't read PROXY header")?;
+        match &self.next {
+            Some(next) => next.process(stream).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Wraps app of plain listener, pre tls callbacks aren't run for those.
+/// Header is read only when `enabled`, once per connection.
+pub struct ProxyProtocolApp<A> {
+    pub inner: Arc<A>,
+    pub enabled: bool,
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for ProxyProtocolApp<A> {
+    async fn process_new(self: &Arc<Self>, mut stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        if self.enabled
+            && let Err(e) = accept(&mut *stream).await
+        {
+            warn!("Dropping connection: {e}");
+            return None;
+        }
+        // reused connections are handled here, so the header is not expected again
+        let mut reused = self.inner.process_new(stream, shutdown).await;
+        while let Some(stream) = reused {
+            reused = self.inner.process_new(stream, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await
+    }
+}
+
+/// Upstream connector sending PROXY header for the client right after connecting.
+#[derive(Debug)]
+pub struct ProxyProtocolConnect {
+    pub version: ProxyProtocolVersion,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[async_trait]
+impl L4Connect for ProxyProtocolConnect {
+    async fn connect(&self, addr: &L4SocketAddr) -> pingora::Result<L4Stream> {
+        let Some(addr) = addr.as_inet() else {
+            return Error::e_explain(ErrorType::ConnectError, "PROXY header can be sent only to inet upstreams");
+        };
+        let mut stream = TcpStream::connect(addr).await.or_err(ErrorType::ConnectError, "Can't connect to upstream")?;
+        stream
+            .write_all(&encode(self.version, self.source, self.destination))
+            .await
+            .or_err(ErrorType::WriteError, "Can't send PROXY header")?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header read from `input` and bytes left after it.
+    async fn read(input: &[u8]) -> (std::io::Result<ProxyHeader>, Vec<u8>) {
+        let mut rest = input;
+        let header = read_header(&mut rest).await;
+        (header, rest.to_vec())
+    }
+
+    fn addr(v: &str) -> Option<SocketAddr> {
+        Some(v.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family << 4 | 1]);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_tcp6_and_unknown() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET /").await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: addr("192.0.2.1:56324"), destination: addr("198.51.100.2:443") });
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 ::1 56324 443\r\n").await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: addr("[2001:db8::1]:56324"), destination: addr("[::1]:443") });
+
+        let (header, rest) = read(b"PROXY UNKNOWN ffff:f...f:ffff 65535\r\n\x16").await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: None, destination: None });
+        assert_eq!(rest, b"\x16");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for input in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 ::1 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 65536\r\n",
+            b"PROXY TCP4 192.0.2.1  198.51.100.2 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 \xff\r\n",
+            b"PROXY TCP4 192.0.2.1",
+            b"PROXY",
+        ] {
+            assert!(read(input).await.0.is_err(), "{}", String::from_utf8_lossy(input));
+        }
+        let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        assert_eq!(read(long.as_bytes()).await.0.unwrap_err().to_string(), "PROXY v1 header is too long");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_and_local() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 2];
+        payload.extend(56324u16.to_be_bytes());
+        payload.extend(443u16.to_be_bytes());
+        // TLVs after addresses are skipped
+        payload.extend([0x04, 0, 1, 0]);
+        let mut input = v2(1, 1, &payload);
+        input.extend(b"payload");
+        let (header, rest) = read(&input).await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: addr("192.0.2.1:56324"), destination: addr("198.51.100.2:443") });
+        assert_eq!(rest, b"payload");
+
+        let (header, rest) = read(&[v2(0, 0, &[]), b"x".to_vec()].concat()).await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: None, destination: None });
+        assert_eq!(rest, b"x");
+
+        // AF_UNIX keeps socket addresses
+        let (header, _) = read(&v2(1, 3, &[0; 216])).await;
+        assert_eq!(header.unwrap(), ProxyHeader { source: None, destination: None });
+    }
+
+    #[tokio::test]
+    async fn v2_malformed() {
+        assert_eq!(read(&v2(1, 1, &[0; 11])).await.0.unwrap_err().to_string(), "PROXY v2 address block is truncated");
+        assert_eq!(read(&v2(1, 2, &[0; 35])).await.0.unwrap_err().to_string(), "PROXY v2 address block is truncated");
+        assert_eq!(read(&v2(2, 1, &[0; 12])).await.0.unwrap_err().to_string(), "invalid PROXY v2 command");
+        assert_eq!(
+            read(&v2(1, 1, &vec![0; V2_MAX_LEN + 1])).await.0.unwrap_err().to_string(),
+            "PROXY v2 header is too long"
+        );
+        // declared length longer than what was sent
+        let mut input = v2(1, 1, &[0; 12]);
+        input.truncate(20);
+        assert_eq!(read(&input).await.0.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let mut version = v2(1, 1, &[0; 12]);
+        version[12] = 0x11;
+        assert_eq!(read(&version).await.0.unwrap_err().to_string(), "invalid PROXY v2 signature");
+        let mut signature = v2(1, 1, &[0; 12]);
+        signature[10] = b'X';
+        assert_eq!(read(&signature).await.0.unwrap_err().to_string(), "invalid PROXY v2 signature");
+        assert_eq!(read(b"GET / HTTP/1.1\r\n").await.0.unwrap_err().to_string(), "PROXY header is missing");
+        assert_eq!(read(b"\r\n\r\n").await.0.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn encoded_header_is_read_back() {
+        let cases = [
+            ("192.0.2.1:56324", "198.51.100.2:443", "192.0.2.1:56324", "198.51.100.2:443"),
+            ("[2001:db8::1]:56324", "[::1]:443", "[2001:db8::1]:56324", "[::1]:443"),
+            // mixed families are sent as ipv6
+            ("192.0.2.1:56324", "[::1]:443", "[::ffff:192.0.2.1]:56324", "[::1]:443"),
+        ];
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (source, destination, expected_source, expected_destination) in cases {
+                let mut input = encode(version, source.parse().unwrap(), destination.parse().unwrap());
+                input.extend(b"next");
+                let (header, rest) = read(&input).await;
+                let expected = ProxyHeader { source: addr(expected_source), destination: addr(expected_destination) };
+                assert_eq!(header.unwrap(), expected, "{version:?} {source} {destination}");
+                assert_eq!(rest, b"next");
+            }
+        }
+    }
+}
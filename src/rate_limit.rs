@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use http::HeaderName;
+use pingora::http::RequestHeader;
+use pingora_limits::rate::Rate;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRecord {
+    /// Requests allowed per `per` seconds.
+    pub requests: u64,
+    /// Window length in seconds, default 1.
+    pub per: Option<u64>,
+    /// Requests tolerated on top of `requests` in the window, default 0.
+    pub burst: Option<u64>,
+    /// `ip` (default), `header:<name>` or `query:<param>`.
+    pub key: Option<String>,
+}
+
+/// What requests are counted together.
+#[derive(Clone, Debug)]
+pub enum RateLimitKey {
+    Ip,
+    Header(HeaderName),
+    Query(String),
+}
+
+impl RateLimitKey {
+    fn parse(key: &str) -> Result<Self, String> {
+        match key.split_once(':') {
+            None if key == "ip" => Ok(Self::Ip),
+            Some(("header", name)) => name
+                .parse()
+                .map(Self::Header)
+                .map_err(|_| format!("Invalid rate limit header: {name}")),
+            Some(("query", param)) if !param.is_empty() => Ok(Self::Query(param.to_string())),
+            _ => Err(format!("Invalid rate limit key {key}, expected ip, header:<name> or query:<param>")),
+        }
+    }
+}
+
+/// Sliding window limit of one record.
+/// Counters start from zero when config is reloaded.
+pub struct RateLimit {
+    limit: u64,
+    per: Duration,
+    key: RateLimitKey,
+    rate: Rate,
+}
+
+impl std::fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimit").field("limit", &self.limit).field("per", &self.per).field("key", &self.key).finish()
+    }
+}
+
+impl RateLimit {
+    pub fn try_parse(record: RateLimitRecord) -> Result<Self, String> {
+        let RateLimitRecord { requests, per, burst, key } = record;
+        if requests == 0 {
+            return Err("Rate limit requests must be at least 1".to_string());
+        }
+        if per == Some(0) {
+            return Err("Rate limit per must be at least one second".to_string());
+        }
+        let per = Duration::from_secs(per.unwrap_or(1));
+        let key = key.as_deref().map(RateLimitKey::parse).transpose()?.unwrap_or(RateLimitKey::Ip);
+        Ok(Self { limit: requests + burst.unwrap_or(0), per, key, rate: Rate::new(per) })
+    }
+
+    /// Requests without the configured header or query parameter are counted by client ip.
+    fn key(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Vec<u8> {
+        let value = match &self.key {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header(name) => req.headers.get(name).map(|v| v.as_bytes()),
+            RateLimitKey::Query(param) => req.uri.query().and_then(|q| {
+                q.split('&').find_map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (name == param).then_some(value.as_bytes())
+                })
+            }),
+        };
+        match value {
+            Some(value) => value.to_vec(),
+            None => client_ip.map(|ip| ip.to_string().into_bytes()).unwrap_or_default(),
+        }
+    }
+
+    /// Counts the request, or returns time after which it would be allowed.
+    /// Rejected requests are not counted, so `Retry-After` holds for clients that respect it.
+    pub fn check(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Result<(), Duration> {
+        let key = self.key(req, client_ip);
+        let retry_after = self.rate.rate_with(&key, |c| {
+            self.retry_after(c.prev_samples as f64, c.curr_samples as f64, c.current_interval_fraction)
+        });
+        match retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => {
+                self.rate.observe(&key, 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Previous window is weighted by the part of it still inside the sliding window,
+    /// `elapsed` is the fraction of the current one that has passed.
+    fn retry_after(&self, prev: f64, curr: f64, elapsed: f64) -> Option<Duration> {
+        let limit = self.limit as f64;
+        if prev * (1. - elapsed) + curr + 1. <= limit {
+            return None;
+        }
+        let wait = if curr + 1. <= limit {
+            // enough of the previous window has to slide out
+            1. - (limit - curr - 1.) / prev - elapsed
+        } else {
+            // current window has to end and partly slide out too
+            1. - elapsed + 1. - (limit - 1.) / curr
+        };
+        Some(self.per.mul_f64(wait.clamp(0., 2.)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(requests: u64, per: u64, key: &str) -> RateLimit {
+        let record = RateLimitRecord { requests, per: Some(per), burst: Some(1), key: Some(key.to_string()) };
+        RateLimit::try_parse(record).unwrap()
+    }
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn ip(v: &str) -> Option<IpAddr> {
+        Some(v.parse().unwrap())
+    }
+
+    /// Counts `times` requests, returns results of the allowed ones and the first rejection.
+    fn allowed(limit: &RateLimit, req: &RequestHeader, client_ip: Option<IpAddr>, times: usize) -> usize {
+        (0..times).take_while(|_| limit.check(req, client_ip).is_ok()).count()
+    }
+
+    #[test]
+    fn retry_after_follows_sliding_window() {
+        let limit = rate_limit(4, 10, "ip");
+        let wait = |prev: f64, curr: f64, elapsed: f64| limit.retry_after(prev, curr, elapsed).map(|d| d.as_secs_f64());
+        let close = |wait: Option<f64>, expected: f64| wait.is_some_and(|w| (w - expected).abs() < 1e-6);
+        // limit is requests + burst = 5
+        assert_eq!(wait(0., 4., 0.5), None);
+        assert!(close(wait(0., 5., 0.5), 7.));
+        assert!(close(wait(10., 0., 0.2), 4.));
+        assert_eq!(wait(10., 0., 0.6), None);
+        assert!(close(wait(10., 5., 0.9), 3.));
+        assert!(close(wait(0., 50., 0.), 19.2));
+    }
+
+    #[test]
+    fn ip_key_counts_clients_separately() {
+        let limit = rate_limit(2, 60, "ip");
+        let req = request("/", &[]);
+        assert_eq!(allowed(&limit, &req, ip("192.0.2.1"), 5), 3);
+        let retry_after = limit.check(&req, ip("192.0.2.1")).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(120), "{retry_after:?}");
+        assert_eq!(allowed(&limit, &req, ip("192.0.2.2"), 5), 3);
+    }
+
+    #[test]
+    fn header_key_falls_back_to_ip() {
+        let limit = rate_limit(1, 60, "header:X-Api-Key");
+        let a = request("/", &[("x-api-key", "a")]);
+        let b = request("/", &[("x-api-key", "b")]);
+        assert_eq!(allowed(&limit, &a, ip("192.0.2.1"), 5), 2);
+        assert_eq!(allowed(&limit, &a, ip("192.0.2.2"), 5), 0, "same key from another client");
+        assert_eq!(allowed(&limit, &b, ip("192.0.2.1"), 5), 2);
+        let missing = request("/", &[]);
+        assert_eq!(allowed(&limit, &missing, ip("192.0.2.1"), 5), 2);
+        assert_eq!(allowed(&limit, &missing, ip("192.0.2.2"), 5), 2);
+    }
+
+    #[test]
+    fn query_key_falls_back_to_ip() {
+        let limit = rate_limit(1, 60, "query:api_key");
+        assert_eq!(allowed(&limit, &request("/?x=1&api_key=a", &[]), ip("192.0.2.1"), 5), 2);
+        assert_eq!(allowed(&limit, &request("/other?api_key=a", &[]), ip("192.0.2.2"), 5), 0);
+        assert_eq!(allowed(&limit, &request("/?api_key=b", &[]), ip("192.0.2.1"), 5), 2);
+        // empty value is a key of its own
+        assert_eq!(allowed(&limit, &request("/?api_key", &[]), ip("192.0.2.1"), 5), 2);
+        assert_eq!(allowed(&limit, &request("/?api_key=", &[]), ip("192.0.2.2"), 5), 0);
+        assert_eq!(allowed(&limit, &request("/?api_keys=a", &[]), ip("192.0.2.3"), 5), 2);
+        assert_eq!(allowed(&limit, &request("/", &[]), ip("192.0.2.3"), 5), 0);
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        for key in ["cookie:x", "query:", "header:bad header", "IP"] {
+            assert!(RateLimitKey::parse(key).is_err(), "{key}");
+        }
+        assert!(RateLimit::try_parse(RateLimitRecord { requests: 0, per: None, burst: None, key: None }).is_err());
+        assert!(RateLimit::try_parse(RateLimitRecord { requests: 1, per: Some(0), burst: None, key: None }).is_err());
+    }
+}
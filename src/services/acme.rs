@@ -0,0 +1,336 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, anyhow, bail};
+use async_trait::async_trait;
+use http::Response;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus,
+    RetryPolicy,
+};
+use pingora::apps::http_app::ServeHttp;
+use pingora::listeners::PreTlsProcess;
+use pingora::protocols::http::ServerSession;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::{Error, ErrorType};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::ServerConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::sign::{CertifiedKey, SingleCertAndKey};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+use crate::certs::CertResolver;
+use crate::config::SharedConfig;
+use crate::services::tcp::{HELLO_TIMEOUT, read_client_hello};
+
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+const ACCOUNT_FILE: &str = "acme-account.json";
+/// Mode of files with private keys.
+const PRIVATE_MODE: u32 = 0o600;
+/// Certificates are renewed when they expire sooner than this.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Delay before the next attempt for a domain after failed issuance.
+const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Pending challenge responses, shared between issuance and the listeners answering them.
+#[derive(Default)]
+pub struct AcmeChallenges {
+    /// Key authorizations by http-01 token.
+    http: Mutex<HashMap<String, String>>,
+    /// tls-alpn-01 certificates by domain.
+    tls_alpn: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl AcmeChallenges {
+    /// Key authorization for http-01 challenge request path.
+    pub fn http_response(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PREFIX)?;
+        self.http.lock().unwrap().get(token).cloned()
+    }
+}
+
+/// Answers http-01 challenges on plain http listener.
+pub struct AcmeHttp {
+    pub challenges: Arc<AcmeChallenges>,
+}
+
+#[async_trait]
+impl ServeHttp for AcmeHttp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let key_auth = self.challenges.http_response(session.req_header().uri.path());
+        let (status, body) = match key_auth {
+            Some(key_auth) => (200, key_auth.into_bytes()),
+            None => (404, Vec::new()),
+        };
+        Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap_or_default()
+    }
+}
+
+/// Answers tls-alpn-01 challenges before the proxy TLS handshake.
+/// Connections offering `acme-tls/1` are handshaken with the challenge
+/// certificate and closed, others are passed on untouched.
+pub struct AcmeTlsAlpn {
+    pub challenges: Arc<AcmeChallenges>,
+}
+
+#[async_trait]
+impl PreTlsProcess for AcmeTlsAlpn {
+    async fn process(&self, stream: &mut L4Stream) -> pingora::Result<()> {
+        let (hello, info) = match tokio::time::timeout(HELLO_TIMEOUT, read_client_hello(stream)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => return Error::e_because(ErrorType::ReadError, "reading client hello", e),
+            Err(_) => return Error::e_explain(ErrorType::ReadTimedout, "reading client hello"),
+        };
+        stream.rewind(&hello);
+        if !info.alpn.iter().any(|p| p == ACME_TLS_ALPN) {
+            return Ok(());
+        }
+        let sni = info.sni.unwrap_or_default().to_ascii_lowercase();
+        let Some(cert) = self.challenges.tls_alpn.lock().unwrap().get(&sni).cloned() else {
+            return Error::e_explain(ErrorType::HandshakeError, format!("no acme challenge pending for {sni}"));
+        };
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(cert)));
+        config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+        match tokio_rustls::TlsAcceptor::from(Arc::new(config)).accept(&mut *stream).await {
+            Ok(mut tls) => {
+                let _ = tls.shutdown().await;
+                info!("Answered tls-alpn-01 challenge for {sni}");
+            }
+            Err(e) => warn!("Tls-alpn-01 handshake for {sni} failed: {e}"),
+        }
+        // validation connections never carry application data
+        Error::e_explain(ErrorType::ConnectionClosed, "acme challenge answered")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredAccount {
+    directory: String,
+    credentials: AccountCredentials,
+}
+
+/// Obtains and renews certificates for all http and dir domains in current config.
+/// Certificates are stored as `<cert_path>/<domain>/fullchain.pem` and `privkey.pem`,
+/// where [`CertResolver`] picks them up.
+pub struct AcmeService {
+    pub config: SharedConfig,
+    pub certs: Arc<CertResolver>,
+    pub challenges: Arc<AcmeChallenges>,
+    pub cert_path: PathBuf,
+    pub directory: String,
+    /// Contact email, may be empty.
+    pub email: String,
+    /// Extra root certificate of the ACME server, for test CAs like Pebble.
+    pub ca_root: Option<String>,
+    pub challenge: ChallengeType,
+}
+
+impl AcmeService {
+    fn domains(&self) -> BTreeSet<String> {
+        let config = self.config.load();
+        config
+            .http
+            .keys()
+            .chain(config.dir.domain.keys())
+            .filter(|d| !d.is_empty() && !d.contains('*') && d.parse::<IpAddr>().is_err())
+            .map(|d| d.to_ascii_lowercase())
+            .collect()
+    }
+
+    /// True when certificate for the domain is missing or expires soon.
+    fn needs_cert(&self, domain: &str) -> bool {
+        let path = self.cert_path.join(domain).join("fullchain.pem");
+        let Ok(Some(Ok(cert))) = CertificateDer::pem_file_iter(&path).map(|mut certs| certs.next()) else {
+            return true;
+        };
+        let Ok((_, cert)) = x509_parser::parse_x509_certificate(&cert) else {
+            warn!("Can't parse certificate {}, replacing it", path.display());
+            return true;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        cert.validity().not_after.timestamp() - now < RENEW_BEFORE.as_secs() as i64
+    }
+
+    async fn account(&self) -> anyhow::Result<Account> {
+        let builder = || match &self.ca_root {
+            Some(root) => Account::builder_with_root(root),
+            None => Account::builder(),
+        };
+        let path = self.cert_path.join(ACCOUNT_FILE);
+        if let Ok(data) = std::fs::read(&path)
+            && let Ok(stored) = serde_json::from_slice::<StoredAccount>(&data)
+            && stored.directory == self.directory
+        {
+            return Ok(builder()?.from_credentials(stored.credentials).await?);
+        }
+        let contact = format!("mailto:{}", self.email);
+        let contacts = if self.email.is_empty() { vec![] } else { vec![contact.as_str()] };
+        let new = NewAccount { contact: &contacts, terms_of_service_agreed: true, only_return_existing: false };
+        let (account, credentials) = builder()?.create(&new, self.directory.clone(), None).await?;
+        let stored = StoredAccount { directory: self.directory.clone(), credentials };
+        // holds the account key
+        write_file(&path, &serde_json::to_vec_pretty(&stored)?, PRIVATE_MODE)?;
+        info!("Created acme account {}", account.id());
+        Ok(account)
+    }
+
+    async fn issue(&self, account: &Account, domain: &str) -> anyhow::Result<()> {
+        let mut tokens = Vec::new();
+        let result = self.order(account, domain, &mut tokens).await;
+        let mut http = self.challenges.http.lock().unwrap();
+        for token in tokens {
+            http.remove(&token);
+        }
+        self.challenges.tls_alpn.lock().unwrap().remove(domain);
+        result
+    }
+
+    /// Runs the order, registered http-01 tokens are pushed to `tokens` for cleanup.
+    async fn order(&self, account: &Account, domain: &str, tokens: &mut Vec<String>) -> anyhow::Result<()> {
+        let identifiers = [Identifier::Dns(domain.to_string())];
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+        let mut authorizations = order.authorizations();
+        while let Some(authz) = authorizations.next().await {
+            let mut authz = authz?;
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("authorization is {status:?}"),
+            }
+            let mut challenge = authz
+                .challenge(self.challenge.clone())
+                .ok_or_else(|| anyhow!("no {:?} challenge offered", self.challenge))?;
+            let key_auth = challenge.key_authorization();
+            match self.challenge {
+                ChallengeType::Http01 => {
+                    tokens.push(challenge.token.clone());
+                    self.challenges.http.lock().unwrap().insert(challenge.token.clone(), key_auth.as_str().to_string());
+                }
+                ChallengeType::TlsAlpn01 => {
+                    let cert = tls_alpn_cert(domain, key_auth.digest().as_ref())?;
+                    self.challenges.tls_alpn.lock().unwrap().insert(domain.to_string(), cert);
+                }
+                _ => bail!("unsupported challenge {:?}", self.challenge),
+            }
+            challenge.set_ready().await?;
+        }
+
+        let retries = RetryPolicy::new().timeout(Duration::from_secs(120));
+        let status = order.poll_ready(&retries).await?;
+        if status != OrderStatus::Ready {
+            bail!("order is {status:?}");
+        }
+        let key = order.finalize().await?;
+        let chain = order.poll_certificate(&retries).await?;
+
+        let dir = self.cert_path.join(domain);
+        std::fs::create_dir_all(&dir).with_context(|| format!("can't create {}", dir.display()))?;
+        self.certs.replace_files(|| {
+            write_file(&dir.join("fullchain.pem"), chain.as_bytes(), 0o644)?;
+            write_file(&dir.join("privkey.pem"), key.as_bytes(), PRIVATE_MODE)
+        })
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AcmeService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut account = None;
+        let mut account_retry_at = Instant::now();
+        let mut retry_at: HashMap<String, Instant> = HashMap::new();
+        let mut tick = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        return;
+                    }
+                    continue;
+                }
+                _ = tick.tick() => {}
+            }
+            for domain in self.domains() {
+                if retry_at.get(&domain).is_some_and(|at| *at > Instant::now()) || !self.needs_cert(&domain) {
+                    continue;
+                }
+                // account is only needed once some domain requires a certificate
+                if account.is_none() {
+                    if account_retry_at > Instant::now() {
+                        break;
+                    }
+                    match self.account().await {
+                        Ok(v) => account = Some(v),
+                        Err(e) => {
+                            error!("Can't get acme account from {}, retrying in {}s: {e:#}", self.directory, RETRY_AFTER.as_secs());
+                            account_retry_at = Instant::now() + RETRY_AFTER;
+                            break;
+                        }
+                    }
+                }
+                let Some(account) = &account else { continue };
+                info!("Requesting certificate for {domain}");
+                match self.issue(account, &domain).await {
+                    Ok(()) => {
+                        info!("Got certificate for {domain}");
+                        retry_at.remove(&domain);
+                        self.certs.reload();
+                    }
+                    Err(e) => {
+                        warn!("Can't get certificate for {domain}, retrying in {}s: {e:#}", RETRY_AFTER.as_secs());
+                        retry_at.insert(domain, Instant::now() + RETRY_AFTER);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Self-signed certificate with acmeIdentifier extension, see RFC 8737.
+fn tls_alpn_cert(domain: &str, digest: &[u8]) -> anyhow::Result<Arc<CertifiedKey>> {
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+    let provider = CryptoProvider::get_default().context("crypto provider is not installed")?;
+    // from_der checks the key against parsed certificate, which rejects the critical acmeIdentifier
+    let key = provider.key_provider.load_private_key(key)?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert.der().clone()], key)))
+}
+
+/// Writes through a temporary file, so readers never see partial content.
+/// `mode` is applied on unix.
+fn write_file(path: &Path, data: &[u8], mode: u32) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // leftover file would keep its mode
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(&tmp)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, data))
+        .with_context(|| format!("can't write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("can't write {}", path.display()))?;
+    Ok(())
+}
@@ -1,7 +1,9 @@
 use axum::{
     Router,
 };
-use tracing::warn;
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+use tokio::{runtime::Handle, task::JoinHandle};
+use tracing::{info, warn};
 use tower_http::{
     services::{ServeDir},
     trace::TraceLayer,
@@ -30,3 +32,62 @@ pub fn dirs_router(dirs: Vec<DirParsedRecord>) -> Router {
     app
         .layer(TraceLayer::new_for_http())
 }
+
+/// What a dir server serves, other record changes don't need a restart.
+fn served(dirs: &[DirParsedRecord]) -> Vec<(&std::path::Path, &str)> {
+    dirs.iter().map(|d| (d.path.as_path(), d.route.as_str())).collect()
+}
+
+type RunningDir = (Vec<DirParsedRecord>, JoinHandle<()>);
+
+/// Running dir servers by listen address.
+/// Servers are restarted only when their set of records changes.
+pub struct DirServers {
+    runtime: Handle,
+    running: Mutex<HashMap<SocketAddr, RunningDir>>,
+}
+
+impl DirServers {
+    pub fn new(runtime: Handle) -> Self {
+        Self { runtime, running: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn apply(&self, listen: &HashMap<SocketAddr, Vec<DirParsedRecord>>) {
+        let mut running = self.running.lock().unwrap();
+        let mut stopped = HashMap::new();
+        for host in running.keys().cloned().collect::<Vec<_>>() {
+            if listen.get(&host).map(|dirs| served(dirs)) == running.get(&host).map(|(dirs, _)| served(dirs)) {
+                continue;
+            }
+            let Some((_, task)) = running.remove(&host) else { continue };
+            info!("Stopping dir server on {}", host);
+            task.abort();
+            stopped.insert(host, task);
+        }
+        for (host, dirs) in listen.iter() {
+            if running.contains_key(host) {
+                continue;
+            }
+            let host = *host;
+            let r = dirs_router(dirs.clone());
+            let previous = stopped.remove(&host);
+            let task = self.runtime.spawn(async move {
+                // wait for the replaced server to release the port
+                if let Some(previous) = previous {
+                    let _ = previous.await;
+                }
+                let l = tokio::net::TcpListener::bind(host).await;
+                let Ok(listener) = l else {
+                    warn!("Can't bind port for dir server: {}", l.unwrap_err());
+                    return;
+                };
+                info!("Starting dir server on {}", host);
+                if let Err(e) = axum::serve(listener, r).await
+                {
+                    tracing::error!("Dir server on {} failed: {}", host, e);
+                }
+            });
+            running.insert(host, (dirs.clone(), task));
+        }
+    }
+}
@@ -1,26 +1,120 @@
 use async_trait::async_trait;
-use http::Uri;
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue, Uri};
 use pingora::{prelude::*};
+use pingora::protocols::http::ServerSession;
+use pingora::proxy::FailToProxy;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tracing::{info, Level, Span};
+use std::time::{Duration, Instant};
+use tracing::{info, warn, Level, Span};
 use tracing::span;
 use uuid::Uuid;
-use crate::config::RouteConfig;
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::basic_auth::BasicAuth;
+use crate::client_cert::{self, ClientCert};
+use crate::config::{HttpParsedRecord, SharedConfig};
+use crate::forward_auth::{ForwardAuth, Verdict};
+use crate::forwarded;
+use crate::jwt::Jwt;
+use crate::ip_filter::IpFilter;
+use crate::proxy_protocol::ProxyProtocolConnect;
+use crate::rate_limit::RateLimit;
+use crate::metrics::{DIR_HITS, HttpRequestMetrics, UPSTREAM_CONNECT_ERRORS};
+use crate::services::acme::AcmeChallenges;
+
+/// Concurrent streams on one HTTP/2 upstream connection.
+const H2_STREAMS: usize = 100;
 
 pub struct HttpGateway {
-    pub config: Arc<RouteConfig>,
+    pub config: SharedConfig,
+    /// Address of `[[listener]]` record whose domain list is applied, `None` accepts all.
+    pub listener: Option<std::net::SocketAddr>,
+    /// Set for plain http listener next to https one.
+    pub redirect: Option<HttpsRedirect>,
+    pub access_log: Option<Arc<AccessLog>>,
 }
 
-
+/// Plain http listener only proxies `plain_http` records,
+/// other known domains are redirected to https.
+pub struct HttpsRedirect {
+    pub https_port: u16,
+    /// Pending http-01 challenges, answered before anything else.
+    pub acme: Option<Arc<AcmeChallenges>>,
+}
 
 pub struct Context {
-    pub span: Arc<Span>
+    pub span: Arc<Span>,
+    pub request_id: String,
+    /// Path as requested by client, uri is rewritten for upstream later.
+    pub request_path: Option<String>,
+    /// Direct peer, or address reported by trusted proxy.
+    pub client_ip: Option<IpAddr>,
+    /// Peer selected by request filter.
+    pub peer: Option<Box<HttpPeer>>,
+    pub metrics: HttpRequestMetrics,
+    /// Set when upstream peer is selected, used for upstream latency.
+    pub upstream_start: Option<Instant>,
+    pub upstream_latency: Option<Duration>,
+    /// Upstream connection carries PROXY header of this client and must not be reused.
+    pub close_upstream: bool,
+    /// Set for upgrade requests (e.g. WebSocket) routed to http record.
+    pub upgrade: Option<Upgrade>,
+    /// Set when matched record rejects request over its rate limit.
+    pub retry_after: Option<Duration>,
+    /// Set once ip filter and rate limit of matched record let request through.
+    pub policies_checked: bool,
+    // credentials the matched record requires, `authorize` checks them before proxying
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    pub jwt: Option<Arc<Jwt>>,
+    /// Basic auth user name or token `sub`, logged as `user`.
+    pub user: Option<String>,
+    /// Headers granted by forward auth and token claims, sent to upstream.
+    pub auth_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Timeouts and traffic of upgraded connection.
+pub struct Upgrade {
+    /// Falls back to downstream read timeout when unset.
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// Set once upstream switches protocols.
+    pub started: Option<Instant>,
+    /// Bytes sent to upstream.
+    pub sent: u64,
+    /// Bytes received from upstream.
+    pub received: u64,
+}
+
+impl Upgrade {
+    /// Downstream read timeout until the next frame in either direction.
+    /// Every frame restarts the read, so it fires only when both sides are idle
+    /// or the lifetime is over.
+    fn read_timeout(&self) -> pingora::Result<Option<Duration>> {
+        let remaining = match (self.started, self.max_lifetime) {
+            (Some(started), Some(max)) => match max.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Error::e_explain(ReadTimedout, "upgraded connection reached max lifetime"),
+            },
+            _ => None,
+        };
+        Ok(match (self.idle_timeout, remaining) {
+            (Some(idle), Some(remaining)) => Some(idle.min(remaining)),
+            (idle, remaining) => idle.or(remaining),
+        })
+    }
 }
 
 impl HttpGateway {
     pub fn default_err() -> Box<Error> {
+        Self::status_err(404)
+    }
+
+    pub fn status_err(code: u16) -> Box<Error> {
         Box::new(Error{
-            etype: ErrorType::HTTPStatus(404),
+            etype: ErrorType::HTTPStatus(code),
             esource: ErrorSource::Upstream,
             retry: false.into(),
             context: None,
@@ -28,24 +122,235 @@ impl HttpGateway {
         })
     }
 
-    pub fn handle_upstream_peer(
-        &self,
-        session: &mut Session,
-    ) -> Result<Option<HttpPeer>, String> {
-        let host = session.req_header().headers.get("host").cloned();
-        let host = host
+    /// Key for hashing strategies: configured header value or client ip.
+    fn balance_key(session: &Session, cfg: &HttpParsedRecord, client_ip: Option<IpAddr>) -> Vec<u8> {
+        if let Some(value) = cfg.hash_header.as_ref().and_then(|h| session.req_header().headers.get(h)) {
+            return value.as_bytes().to_vec();
+        }
+        client_ip.map(|ip| ip.to_string().into_bytes()).unwrap_or_default()
+    }
+
+    fn peer_ip(session: &Session) -> Option<IpAddr> {
+        session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip())
+    }
+
+    /// Host header, or authority of HTTP/2 requests which don't carry it.
+    fn request_host(session: &Session) -> String {
+        let req = session.req_header();
+        req.headers
+            .get("host")
             .and_then(|v| v.to_str().map(|v| v.to_string()).ok())
-            .unwrap_or_default();
+            .or_else(|| req.uri.authority().map(|a| a.to_string()))
+            .unwrap_or_default()
+    }
+
+    /// Host matched against records and listener domains, lowercase and without port.
+    fn route_host(host: &str) -> String {
+        match host.parse::<http::uri::Authority>() {
+            Ok(authority) => authority.host().to_ascii_lowercase(),
+            Err(_) => host.to_ascii_lowercase(),
+        }
+    }
+
+    /// Label of http record in metrics.
+    fn record_label(cfg: &HttpParsedRecord) -> String {
+        if cfg.routes.is_empty() { "http /".to_string() } else { format!("http {}", cfg.routes.join(",")) }
+    }
+
+    /// Request id sent by trusted client, if it looks sane.
+    fn incoming_request_id(session: &Session) -> Option<String> {
+        let ip = Self::peer_ip(session)?;
+        if !crate::CFG.REQUEST_ID_TRUSTED.contains(&ip) {
+            return None;
+        }
+        let id = session.req_header().headers.get(&crate::CFG.REQUEST_ID_HEADER)?.to_str().ok()?;
+        let valid = !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| id.to_string())
+    }
+
+    fn access_log_entry(session: &Session, ctx: &Context, status: u16) -> AccessLogEntry {
+        let req = session.req_header();
+        // non-ASCII values are kept, formats escape them
+        let header = |name: http::header::HeaderName| {
+            req.headers.get(name).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()).unwrap_or_default()
+        };
+        AccessLogEntry {
+            time: chrono::Utc::now(),
+            request_id: ctx.request_id.clone(),
+            client_ip: ctx.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            user: ctx.user.clone().unwrap_or_default(),
+            host: Self::request_host(session),
+            method: req.method.to_string(),
+            path: ctx.request_path.clone().unwrap_or_else(|| req.uri.to_string()),
+            protocol: format!("{:?}", req.version),
+            rule: ctx.metrics.record.clone(),
+            upstream: ctx.metrics.upstream.clone(),
+            status,
+            bytes: session.body_bytes_sent(),
+            upstream_latency: ctx.upstream_latency,
+            latency: ctx.metrics.elapsed(),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            upgrade_duration: ctx.upgrade.as_ref().and_then(|u| u.started).map(|s| s.elapsed()),
+            upgrade_bytes_sent: ctx.upgrade.as_ref().map_or(0, |u| u.sent),
+            upgrade_bytes_received: ctx.upgrade.as_ref().map_or(0, |u| u.received),
+        }
+    }
+
+    /// 403 for clients rejected by allow or deny list of matched record.
+    fn check_ip_filter(ctx: &Context, host: &str, ip_filter: Option<&IpFilter>) -> pingora::Result<()> {
+        let Some(rule) = ip_filter.and_then(|f| f.denied_by(ctx.client_ip)) else { return Ok(()) };
+        let client = ctx.client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+        info!("Client {client} denied by {rule} of {host} {}! Rejecting...", ctx.metrics.record);
+        Err(Self::status_err(403))
+    }
+
+    /// Ip filter and rate limit of matched record, checked once even if upstream peer is selected again on retry.
+    fn check_policies(
+        session: &Session,
+        ctx: &mut Context,
+        host: &str,
+        ip_filter: Option<&IpFilter>,
+        rate_limit: Option<&RateLimit>,
+    ) -> pingora::Result<()> {
+        if ctx.policies_checked {
+            return Ok(());
+        }
+        Self::check_ip_filter(ctx, host, ip_filter)?;
+        Self::check_rate_limit(session, ctx, rate_limit)?;
+        ctx.policies_checked = true;
+        Ok(())
+    }
+
+    /// Counts request towards rate limit of matched record, 429 when it is over.
+    fn check_rate_limit(session: &Session, ctx: &mut Context, rate_limit: Option<&RateLimit>) -> pingora::Result<()> {
+        let Some(rate_limit) = rate_limit else { return Ok(()) };
+        if let Err(retry_after) = rate_limit.check(session.req_header(), ctx.client_ip) {
+            info!("Rate limit exceeded, retry after {:.3}s! Rejecting...", retry_after.as_secs_f64());
+            ctx.metrics.rate_limited();
+            ctx.retry_after = Some(retry_after);
+            return Err(Self::status_err(429));
+        }
+        Ok(())
+    }
+
+    async fn respond_rate_limited(session: &mut Session, ctx: &Context, retry_after: Duration) -> pingora::Result<()> {
+        let mut resp = ResponseHeader::build(429, Some(3))?;
+        resp.insert_header(http::header::RETRY_AFTER, retry_after.as_secs_f64().ceil().max(1.).to_string())?;
+        resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &ctx.request_id)?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        session.write_response_header(Box::new(resp), true).await
+    }
+
+    fn hop(session: &Session, ctx: &Context) -> forwarded::Hop {
+        forwarded::Hop {
+            peer: Self::peer_ip(session),
+            client_ip: ctx.client_ip,
+            tls: session.digest().is_some_and(|d| d.ssl_digest.is_some()),
+            host: Self::request_host(session),
+            port: session.server_addr().and_then(|a| a.as_inet()).map(|a| a.port()),
+        }
+    }
+
+    /// Checks credentials required by matched record, answers rejected requests itself.
+    /// Returns whether the response was written.
+    async fn authorize(session: &mut Session, ctx: &mut Context) -> pingora::Result<bool> {
+        if let Some(auth) = ctx.basic_auth.clone() {
+            let authorization = session.req_header().headers.get(http::header::AUTHORIZATION);
+            match auth.authenticate(authorization).await {
+                Some(user) => ctx.user = Some(user),
+                None => {
+                    info!("Basic auth required! Rejecting...");
+                    let mut resp = ResponseHeader::build(401, Some(3))?;
+                    resp.insert_header(http::header::WWW_AUTHENTICATE, auth.challenge())?;
+                    resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &ctx.request_id)?;
+                    resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+                    session.write_response_header(Box::new(resp), true).await?;
+                    return Ok(true);
+                }
+            }
+        }
+        if let Some(jwt) = ctx.jwt.clone() {
+            let authorization = session.req_header().headers.get(http::header::AUTHORIZATION);
+            match jwt.validate(authorization) {
+                Ok(claims) => {
+                    ctx.user = claims.subject.or(ctx.user.take());
+                    ctx.auth_headers.extend(claims.headers);
+                }
+                Err(reason) => {
+                    info!("Jwt rejected, {reason}! Rejecting...");
+                    // RFC 6750, clients without token only get the scheme
+                    let challenge = if authorization.is_some() { "Bearer error=\"invalid_token\"" } else { "Bearer" };
+                    let mut resp = ResponseHeader::build(401, Some(3))?;
+                    resp.insert_header(http::header::WWW_AUTHENTICATE, challenge)?;
+                    resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &ctx.request_id)?;
+                    resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+                    session.write_response_header(Box::new(resp), true).await?;
+                    return Ok(true);
+                }
+            }
+        }
+        if let Some(auth) = ctx.forward_auth.clone() {
+            let uri = ctx.request_path.clone().unwrap_or_default();
+            let hop = Self::hop(session, ctx);
+            let verdict = auth.check(session.req_header(), &uri, &hop, &crate::CFG.TRUSTED_PROXIES, &ctx.request_id).await;
+            match verdict {
+                Ok(Verdict::Granted(headers)) => ctx.auth_headers.extend(headers),
+                Ok(Verdict::Denied(mut resp, body)) => {
+                    info!("Forward auth answered {}! Rejecting...", resp.status);
+                    resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &ctx.request_id)?;
+                    session.write_response_header(resp, body.is_empty()).await?;
+                    if !body.is_empty() {
+                        session.write_response_body(Some(body), true).await?;
+                    }
+                    return Ok(true);
+                }
+                Err(e) => {
+                    warn!("Forward auth failed: {e}");
+                    return Err(Self::status_err(502));
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn handle_upstream_peer(&self, session: &mut Session, ctx: &mut Context) -> Result<Option<HttpPeer>, Box<Error>> {
+        let host = Self::request_host(session);
         info!("Requested host: {}", host);
+        // authority is replaced by upstream address below, host has to survive for upstream
+        if !host.is_empty() && !session.req_header().headers.contains_key(http::header::HOST) {
+            session.req_header_mut().insert_header(http::header::HOST, &host)?;
+        }
+        let host = Self::route_host(&host);
+        let is_upgrade = session.is_upgrade_req();
+        // only HTTP/1.1 connections can switch protocols, anything else would be proxied as plain request
+        if !is_upgrade && session.req_header().headers.contains_key(http::header::UPGRADE) {
+            info!("Upgrade requested over {:?}! Rejecting...", session.req_header().version);
+            return Err(Self::status_err(400));
+        }
         let pq = session.req_header().uri.path_and_query();
+        let config = self.config.load();
+        if let Some(addr) = self.listener
+            && let Some(listener) = config.listener.iter().find(|l| l.addr == addr)
+            && !listener.accepts(&host)
+        {
+            info!("Host {host} is not accepted on {addr}");
+            return Ok(None);
+        }
         'a: {
-        if let Some(dirs) = self.config.dir.domain.get(&host) {
+        if self.redirect.is_some() {
+            break 'a
+        }
+        if let Some(dirs) = config.dir.domain.get(&host) {
             let Some(pq) = pq else {
                 info!("No path for served dir! Continuing to http...");
                 break 'a
             };
             for dir in dirs.iter() {
                 if pq.path().starts_with(&dir.route) {
+                    ctx.metrics.record = format!("dir {}", dir.route);
+                    Self::check_policies(session, ctx, &host, dir.ip_filter.as_ref(), dir.rate_limit.as_deref())?;
+                    ctx.basic_auth = dir.auth.clone();
                     let mut uri = Uri::builder()
                         .authority(dir.listen.to_string())
                         .scheme("http");
@@ -53,6 +358,8 @@ impl HttpGateway {
                     uri = uri.path_and_query(pq.clone());
                     session.req_header_mut().set_uri(uri.build().unwrap());
                     info!("Redirecting to dir {}", dir.listen);
+                    DIR_HITS.with_label_values(&[&host, &dir.route]).inc();
+                    ctx.metrics.upstream = dir.listen.to_string();
                     return Ok(Some(HttpPeer::new(dir.listen, false, host)));
                 }
             }
@@ -60,14 +367,17 @@ impl HttpGateway {
         }
         }
 
-        let Some(cfgs) = self.config.http.get(&host) else {
+        let Some(cfgs) = config.http.get(&host) else {
             info!("No matching host found! Continuing to http...");
             return Ok(None);
         };
         
         for cfg in cfgs {
+            if self.redirect.is_some() && !cfg.plain_http {
+                continue;
+            }
             let mut uri = Uri::builder().scheme(if cfg.https {"https"} else {"http"});
-            let mut addr = cfg.addr.clone();
+            let mut port_override = None;
             if let Some(pq) = pq {
                 let mut pq = pq.to_string();
 
@@ -85,11 +395,11 @@ impl HttpGateway {
                         info!("Port {port} is not allowed! Skipping...");
                         continue;
                     }
-                    addr.set_port(port);
+                    port_override = Some(port);
                     pq = format!("/{}", rest);
                 }
                 // check routes
-                if cfg.routes.len() > 0 {
+                if !cfg.routes.is_empty() {
                     let mut passed = false;
                     for route in cfg.routes.iter() {
                         if pq.starts_with(route) {
@@ -111,15 +421,62 @@ impl HttpGateway {
                     info!("No path on route that must have port prefix! Skipping...");
                     continue;
                 }
-                if cfg.routes.len() > 0 {
+                if !cfg.routes.is_empty() {
 
                 }
             }
+            ctx.metrics.record = Self::record_label(cfg);
+            Self::check_policies(session, ctx, &host, cfg.ip_filter.as_ref(), cfg.rate_limit.as_deref())?;
+            ctx.basic_auth = cfg.auth.clone();
+            ctx.forward_auth = cfg.forward_auth.clone();
+            ctx.jwt = cfg.jwt.clone();
+            if cfg.client_cert && ClientCert::from_session(session).is_none() {
+                info!("Client certificate required! Rejecting...");
+                return Err(Self::status_err(403));
+            }
+            if is_upgrade && cfg.protocol.is_h2() {
+                info!("Upgrade can't be tunneled to {:?} upstream! Rejecting...", cfg.protocol);
+                return Err(Self::status_err(501));
+            }
+            let Some(backend) = cfg.upstreams.select(&Self::balance_key(session, cfg, ctx.client_ip)) else {
+                warn!("No available upstream for {host}!");
+                return Err(Self::status_err(502));
+            };
+            let mut addr = backend.addr;
+            if let Some(port) = port_override {
+                addr.set_port(port);
+            }
             uri = uri.authority(addr.to_string());
-            let uri = uri.build().map_err(|e| e.to_string())?;
+            let uri = uri.build().or_err(InternalError, "Can't build upstream uri")?;
             info!("Will be proxied to: {}", uri);
             session.req_header_mut().set_uri(uri);
-            return Ok(Some(HttpPeer::new(addr, cfg.https, host)))
+            ctx.metrics.upstream = addr.to_string();
+            ctx.upgrade = is_upgrade.then_some(Upgrade {
+                idle_timeout: cfg.upgrade_idle_timeout,
+                max_lifetime: cfg.upgrade_max_lifetime,
+                started: None,
+                sent: 0,
+                received: 0,
+            });
+            let mut peer = HttpPeer::new(addr, cfg.https, host);
+            if let Some(tls) = &cfg.upstream_tls {
+                tls.apply(&mut peer);
+            }
+            if cfg.protocol.is_h2() {
+                peer.options.set_http_version(2, 2);
+                peer.options.max_h2_streams = H2_STREAMS;
+            }
+            let inet = |a: Option<&pingora::protocols::l4::socket::SocketAddr>| a.and_then(|a| a.as_inet()).copied();
+            if let Some(version) = cfg.proxy_protocol
+                && let (Some(source), Some(destination)) = (inet(session.client_addr()), inet(session.server_addr()))
+            {
+                peer.options.custom_l4 = Some(Arc::new(ProxyProtocolConnect { version, source, destination }));
+                // pooled h2 connections carry PROXY header of this client, so only its streams may share them
+                let mut hasher = DefaultHasher::new();
+                source.hash(&mut hasher);
+                peer.group_key = hasher.finish();
+            }
+            return Ok(Some(peer))
         } 
         info!("Request doesn't match any rule! Skipping...");
         Ok(None)
@@ -133,8 +490,27 @@ impl ProxyHttp for HttpGateway
 
     fn new_ctx(&self) -> Self::CTX {
         let request_id = Uuid::new_v4().simple().to_string();
-        let span = span!(Level::INFO, "", "id" = %format!("\x1b[90m{}\x1b[0m", request_id));
-        Context{ span: Arc::new(span) }
+        // id is recorded in request filter, incoming one may be kept
+        let span = span!(Level::INFO, "", "id" = tracing::field::Empty);
+        Context {
+            span: Arc::new(span),
+            request_id,
+            request_path: None,
+            client_ip: None,
+            peer: None,
+            metrics: HttpRequestMetrics::default(),
+            upstream_start: None,
+            upstream_latency: None,
+            close_upstream: false,
+            upgrade: None,
+            retry_after: None,
+            policies_checked: false,
+            basic_auth: None,
+            forward_auth: None,
+            jwt: None,
+            user: None,
+            auth_headers: Vec::new(),
+        }
     }
 
 
@@ -143,22 +519,85 @@ impl ProxyHttp for HttpGateway
         session: &mut Session,
         _ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let _s = _ctx.span.enter();
-        
-        match self.handle_upstream_peer(session) {
-            Ok(Some(p)) => Ok(Box::new(p)),
-            Err(msg) => {
-                tracing::error!("{}", msg);
-                Err(Self::default_err())
+        let span = Arc::clone(&_ctx.span);
+        let _s = span.enter();
+        _ctx.upstream_start = Some(Instant::now());
+        let peer = match _ctx.peer.take() {
+            Some(peer) => Ok(Some(*peer)),
+            None => self.handle_upstream_peer(session, _ctx),
+        };
+        match peer {
+            Ok(Some(p)) => {
+                // h2 has no Connection header, its pooling is scoped by peer group key
+                _ctx.close_upstream = p.options.custom_l4.is_some() && p.options.alpn.get_max_http_version() == 1;
+                Ok(Box::new(p))
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                Err(e)
             },
             _ => Err(Self::default_err()),
         }
     }
 
 
-    async fn request_filter(&self, _session: &mut Session, _ctx: &mut Self::CTX) -> pingora::Result<bool> {
-        let _s = _ctx.span.enter();
-        Ok(false)
+    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> pingora::Result<bool> {
+        let span = Arc::clone(&_ctx.span);
+        let _s = span.enter();
+        _ctx.request_path = Some(session.req_header().uri.to_string());
+        _ctx.client_ip = Self::peer_ip(session).map(|peer| {
+            forwarded::real_ip(peer, session.req_header(), &crate::CFG.REAL_IP_HEADER, &crate::CFG.TRUSTED_PROXIES)
+        });
+        if let Some(id) = Self::incoming_request_id(session) {
+            _ctx.request_id = id;
+        }
+        _ctx.span.record("id", tracing::field::display(format!("\x1b[90m{}\x1b[0m", _ctx.request_id)));
+        let host = Self::route_host(&Self::request_host(session));
+        let known = self.config.load().has_domain(&host);
+        _ctx.metrics.started(known.then_some(host.as_str()));
+        if let Some(key_auth) = self.redirect.as_ref()
+            .and_then(|r| r.acme.as_ref())
+            .and_then(|a| a.http_response(session.req_header().uri.path()))
+        {
+            let mut resp = ResponseHeader::build(200, Some(1))?;
+            resp.insert_header(http::header::CONTENT_LENGTH, key_auth.len())?;
+            session.write_response_header(Box::new(resp), false).await?;
+            session.write_response_body(Some(key_auth.into()), true).await?;
+            return Ok(true);
+        }
+        // routed here so rejections can carry headers, upstream peer only picks the result up
+        match self.handle_upstream_peer(session, _ctx) {
+            Ok(Some(peer)) => {
+                _ctx.peer = Some(Box::new(peer));
+                return Self::authorize(session, _ctx).await;
+            }
+            Ok(None) => {}
+            Err(_) if let Some(retry_after) = _ctx.retry_after => {
+                Self::respond_rate_limited(session, _ctx, retry_after).await?;
+                return Ok(true);
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                return Err(e);
+            }
+        }
+
+        let Some(redirect) = &self.redirect else {
+            return Err(Self::default_err());
+        };
+        if !known {
+            return Err(Self::default_err());
+        }
+        let port = if redirect.https_port == 443 { String::new() } else { format!(":{}", redirect.https_port) };
+        let pq = session.req_header().uri.path_and_query().map_or("/", |pq| pq.as_str());
+        let location = format!("https://{host}{port}{pq}");
+        info!("Redirecting to {location}");
+        let mut resp = ResponseHeader::build(308, Some(3))?;
+        resp.insert_header(http::header::LOCATION, location)?;
+        resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &_ctx.request_id)?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        session.write_response_header(Box::new(resp), true).await?;
+        Ok(true)
     }
 
     async fn upstream_request_filter(
@@ -171,14 +610,40 @@ impl ProxyHttp for HttpGateway
         Self::CTX: Send + Sync,
     {
         let _s = _ctx.span.enter();
-        let addr = session.client_addr().cloned().unwrap().as_inet().unwrap().ip().to_string();
-        upstream_request
-            .insert_header("X-Forwarded-For", addr.to_string())
-            .unwrap();
-        upstream_request
-            .insert_header("X-Real-Ip", addr.to_string())
-            .unwrap();
-        info!("Headers for {addr} set!");
+        let hop = Self::hop(session, _ctx);
+        forwarded::set_headers(upstream_request, &hop, &crate::CFG.TRUSTED_PROXIES)?;
+        client_cert::set_headers(upstream_request, ClientCert::from_session(session))?;
+        if let Some(auth) = &_ctx.basic_auth {
+            upstream_request.remove_header(&auth.user_header);
+            if auth.strip_header {
+                upstream_request.remove_header(&http::header::AUTHORIZATION);
+            }
+            if let Some(user) = &_ctx.user {
+                upstream_request.insert_header(auth.user_header.clone(), user)?;
+            }
+        }
+        if let Some(auth) = &_ctx.forward_auth {
+            for name in &auth.response_headers {
+                upstream_request.remove_header(name);
+            }
+        }
+        if let Some(jwt) = &_ctx.jwt {
+            for (_, name) in &jwt.claim_headers {
+                upstream_request.remove_header(name);
+            }
+        }
+        for (name, value) in &_ctx.auth_headers {
+            upstream_request.append_header(name.clone(), value)?;
+        }
+        // Host of h2 requests is derived from rewritten uri, upstream gets the requested one
+        if !hop.host.is_empty() {
+            upstream_request.insert_header(http::header::HOST, &hop.host)?;
+        }
+        upstream_request.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &_ctx.request_id)?;
+        if _ctx.close_upstream {
+            upstream_request.insert_header(http::header::CONNECTION, "close")?;
+        }
+        info!("Headers for {:?} set!", _ctx.client_ip);
         Ok(())
     }
 
@@ -196,5 +661,120 @@ impl ProxyHttp for HttpGateway
             "{} response code: {response_code}",
             self.request_summary(session, _ctx)
         );
+        _ctx.metrics.finished(response_code);
+        if let Some(upgrade) = &_ctx.upgrade
+            && let Some(started) = upgrade.started
+        {
+            let duration = started.elapsed();
+            _ctx.metrics.upgraded(duration, upgrade.sent, upgrade.received);
+            info!(
+                "Upgraded connection closed after {:.3}s, {} bytes sent, {} bytes received",
+                duration.as_secs_f64(),
+                upgrade.sent,
+                upgrade.received
+            );
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.write(&Self::access_log_entry(session, _ctx, response_code));
+        }
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        upstream_response.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &_ctx.request_id)?;
+        if upstream_response.status == http::StatusCode::SWITCHING_PROTOCOLS
+            && let Some(upgrade) = _ctx.upgrade.as_mut()
+        {
+            upgrade.started = Some(Instant::now());
+            upgrade.idle_timeout = upgrade.idle_timeout.or(_session.get_read_timeout());
+            _session.set_read_timeout(upgrade.read_timeout()?);
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(upgrade) = _ctx.upgrade.as_mut().filter(|u| u.started.is_some()) {
+            upgrade.sent += body.as_ref().map_or(0, |b| b.len() as u64);
+            session.set_read_timeout(upgrade.read_timeout()?);
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if let Some(upgrade) = _ctx.upgrade.as_mut().filter(|u| u.started.is_some()) {
+            upgrade.received += body.as_ref().map_or(0, |b| b.len() as u64);
+            session.set_read_timeout(upgrade.read_timeout()?);
+        }
+        Ok(None)
+    }
+
+    async fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(start) = _ctx.upstream_start {
+            _ctx.upstream_latency = Some(start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Idle and lifetime limits of upgraded connections are expected, they are logged on close.
+    fn suppress_error_log(&self, _session: &Session, _ctx: &Self::CTX, error: &Error) -> bool {
+        error.etype == ReadTimedout && _ctx.upgrade.as_ref().is_some_and(|u| u.started.is_some())
+    }
+
+    /// Same status as the default, but the response carries request id like the proxied ones.
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, _ctx: &mut Self::CTX) -> FailToProxy {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            let mut resp = ServerSession::generate_error(code);
+            let written = match resp.insert_header(crate::CFG.REQUEST_ID_HEADER.clone(), &_ctx.request_id) {
+                Ok(()) => session.write_error_response(resp, Bytes::new()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                warn!("Failed to send error response to downstream: {e}");
+            }
+        }
+        FailToProxy { error_code: code, can_reuse_downstream: false }
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        UPSTREAM_CONNECT_ERRORS.with_label_values(&["http", &peer._address.to_string()]).inc();
+        e
     }
 }
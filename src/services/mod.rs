@@ -1,3 +1,6 @@
 pub mod dir;
 pub mod tcp;
 pub mod http;
+pub mod reload;
+pub mod upstreams;
+pub mod acme;
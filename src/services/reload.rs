@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::certs::CertResolver;
+use crate::config::{RouteConfig, SharedConfig};
+use crate::load_config;
+use crate::services::dir::DirServers;
+
+/// Reloads config on SIGHUP or when file modification time changes.
+/// New config is swapped in only if it was loaded without any problem,
+/// otherwise the last good one stays active. Certificates are re-read as well.
+pub struct ConfigReloader {
+    pub path: String,
+    pub config: SharedConfig,
+    pub dirs: Arc<DirServers>,
+    /// One per certificate directory of tls listeners.
+    pub certs: Vec<Arc<CertResolver>>,
+    /// Poll interval for file changes, `None` disables polling.
+    pub interval: Option<Duration>,
+}
+
+impl ConfigReloader {
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    pub async fn reload(&self) {
+        for certs in self.certs.iter() {
+            certs.reload();
+        }
+        let current = self.config.load_full();
+        let new = match load_config(&self.path, true, Some(&current)).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to reload config from {}, keeping previous one: {e}", self.path);
+                return;
+            }
+        };
+        let old_ports: HashSet<_> = self.config.load().tcp.keys().copied().collect();
+        let new_ports: HashSet<_> = new.tcp.keys().copied().collect();
+        if old_ports != new_ports {
+            warn!("Tcp ports changed, restart is required to apply listeners: {:?} -> {:?}", old_ports, new_ports);
+        }
+        // domain lists are applied right away, sockets, tls, client CA and PROXY settings are bound on start
+        let bound = |c: &RouteConfig| {
+            c.listener
+                .iter()
+                .map(|l| (l.addr, l.tls, l.cert_path.clone(), l.proxy_protocol, l.client_ca.clone(), l.client_auth))
+                .collect::<Vec<_>>()
+        };
+        if bound(&self.config.load()) != bound(&new) {
+            warn!("Listeners changed, restart is required to apply them");
+        }
+        self.dirs.apply(&new.dir.listen);
+        self.config.store(Arc::new(new));
+        info!("Config reloaded from {}", self.path);
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                warn!("Can't listen for SIGHUP: {e}");
+                None
+            }
+        };
+        let mut interval = self.interval.map(tokio::time::interval);
+        let mut modified = self.modified();
+        loop {
+            let hup = async {
+                #[cfg(unix)]
+                if let Some(hangup) = hangup.as_mut() {
+                    return hangup.recv().await;
+                }
+                std::future::pending().await
+            };
+            let tick = async {
+                match interval.as_mut() {
+                    Some(interval) => interval.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        return;
+                    }
+                }
+                _ = hup => {
+                    info!("Got SIGHUP, reloading config");
+                    modified = self.modified();
+                    self.reload().await;
+                }
+                _ = tick => {
+                    let current = self.modified();
+                    if current == modified {
+                        continue;
+                    }
+                    info!("Config file {} changed, reloading", self.path);
+                    modified = current;
+                    self.reload().await;
+                }
+            }
+        }
+    }
+}
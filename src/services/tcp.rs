@@ -1,192 +1,258 @@
-// use futures::FutureExt;
-// use std::env;
-// use std::sync::atomic::{AtomicBool, Ordering};
-// use tokio::io::{AsyncReadExt, AsyncWriteExt};
-// use tokio::net::{TcpListener, TcpStream};
-// use tokio::sync::broadcast;
-
-// type BoxedError = Box<dyn std::error::Error + Sync + Send + 'static>;
-// static DEBUG: AtomicBool = AtomicBool::new(false);
-// const BUF_SIZE: usize = 1024;
-
-// #[tokio::main]
-// async fn main() -> Result<(), BoxedError> {
-//     let args: Vec<String> = env::args().collect();
-//     let program = args[0].clone();
-
-//     let mut opts = Options::new();
-//     opts.optopt(
-//         "b",
-//         "bind",
-//         "The address on which to listen for incoming requests, defaulting to localhost",
-//         "BIND_ADDR",
-//     );
-//     opts.optopt(
-//         "l",
-//         "local-port",
-//         "The local port to which tcpproxy should bind to, randomly chosen otherwise",
-//         "LOCAL_PORT",
-//     );
-//     opts.optflag("d", "debug", "Enable debug mode w/ connection logging");
-//     opts.optflag("h", "help", "Print usage info and exit");
-//     opts.optflag("V", "version", "Print version info and exit");
-
-
-//     let remote = match matches.free.len() {
-//         1 => matches.free[0].clone(),
-//         _ => {
-//             print_usage(&mut std::io::stderr().lock(), &program, opts);
-//             std::process::exit(-1);
-//         }
-//     };
-
-//     if !remote.contains(':') {
-//         eprintln!("A remote port is required (REMOTE_ADDR:PORT)");
-//         std::process::exit(-1);
-//     }
-
-//     DEBUG.store(matches.opt_present("d"), Ordering::Relaxed);
-//     // let local_port: i32 = matches.opt_str("l").unwrap_or("0".to_string()).parse()?;
-//     let local_port: i32 = matches.opt_str("l").map(|s| s.parse()).unwrap_or(Ok(0))?;
-//     let bind_addr = match matches.opt_str("b") {
-//         Some(addr) => addr,
-//         None => "127.0.0.1".to_owned(),
-//     };
-
-//     forward(&bind_addr, local_port, remote).await
-// }
-
-// async fn forward(bind_ip: &str, local_port: i32, remote: String) -> Result<(), BoxedError> {
-//     // Listen on the specified IP and port
-//     let bind_addr = if !bind_ip.starts_with('[') && bind_ip.contains(':') {
-//         // Correctly format for IPv6 usage
-//         format!("[{}]:{}", bind_ip, local_port)
-//     } else {
-//         format!("{}:{}", bind_ip, local_port)
-//     };
-//     let bind_sock = bind_addr
-//         .parse::<std::net::SocketAddr>()
-//         .expect("Failed to parse bind address");
-//     let listener = TcpListener::bind(&bind_sock).await?;
-//     println!("Listening on {}", listener.local_addr().unwrap());
-
-//     // `remote` should be either the host name or ip address, with the port appended.
-//     // It doesn't get tested/validated until we get our first connection, though!
-
-//     // We leak `remote` instead of wrapping it in an Arc to share it with future tasks since
-//     // `remote` is going to live for the lifetime of the server in all cases.
-//     // (This reduces MESI/MOESI cache traffic between CPU cores.)
-//     let remote: &str = Box::leak(remote.into_boxed_str());
-
-//     // Two instances of this function are spawned for each half of the connection: client-to-server,
-//     // server-to-client. We can't use tokio::io::copy() instead (no matter how convenient it might
-//     // be) because it doesn't give us a way to correlate the lifetimes of the two tcp read/write
-//     // loops: even after the client disconnects, tokio would keep the upstream connection to the
-//     // server alive until the connection's max client idle timeout is reached.
-//     async fn copy_with_abort<R, W>(
-//         read: &mut R,
-//         write: &mut W,
-//         mut abort: broadcast::Receiver<()>,
-//     ) -> tokio::io::Result<usize>
-//     where
-//         R: tokio::io::AsyncRead + Unpin,
-//         W: tokio::io::AsyncWrite + Unpin,
-//     {
-//         let mut copied = 0;
-//         let mut buf = [0u8; BUF_SIZE];
-//         loop {
-//             let bytes_read;
-//             tokio::select! {
-//                 biased;
-
-//                 result = read.read(&mut buf) => {
-//                     use std::io::ErrorKind::{ConnectionReset, ConnectionAborted};
-//                     bytes_read = result.or_else(|e| match e.kind() {
-//                         // Consider these to be part of the proxy life, not errors
-//                         ConnectionReset | ConnectionAborted => Ok(0),
-//                         _ => Err(e)
-//                     })?;
-//                 },
-//                 _ = abort.recv() => {
-//                     break;
-//                 }
-//             }
-
-//             if bytes_read == 0 {
-//                 break;
-//             }
-
-//             // While we ignore some read errors above, any error writing data we've already read to
-//             // the other side is always treated as exceptional.
-//             write.write_all(&buf[0..bytes_read]).await?;
-//             copied += bytes_read;
-//         }
-
-//         Ok(copied)
-//     }
-
-//     loop {
-//         let (mut client, client_addr) = listener.accept().await?;
-
-//         tokio::spawn(async move {
-//             println!("New connection from {}", client_addr);
-
-//             // Establish connection to upstream for each incoming client connection
-//             let mut remote = match TcpStream::connect(remote).await {
-//                 Ok(result) => result,
-//                 Err(e) => {
-//                     eprintln!("Error establishing upstream connection: {e}");
-//                     return;
-//                 }
-//             };
-//             let (mut client_read, mut client_write) = client.split();
-//             let (mut remote_read, mut remote_write) = remote.split();
-
-//             let (cancel, _) = broadcast::channel::<()>(1);
-//             let (remote_copied, client_copied) = tokio::join! {
-//                 copy_with_abort(&mut remote_read, &mut client_write, cancel.subscribe())
-//                     .then(|r| { let _ = cancel.send(()); async { r } }),
-//                 copy_with_abort(&mut client_read, &mut remote_write, cancel.subscribe())
-//                     .then(|r| { let _ = cancel.send(()); async { r } }),
-//             };
-
-//             match client_copied {
-//                 Ok(count) => {
-//                     if DEBUG.load(Ordering::Relaxed) {
-//                         eprintln!(
-//                             "Transferred {} bytes from proxy client {} to upstream server",
-//                             count, client_addr
-//                         );
-//                     }
-//                 }
-//                 Err(err) => {
-//                     eprintln!(
-//                         "Error writing bytes from proxy client {} to upstream server",
-//                         client_addr
-//                     );
-//                     eprintln!("{}", err);
-//                 }
-//             };
-
-//             match remote_copied {
-//                 Ok(count) => {
-//                     if DEBUG.load(Ordering::Relaxed) {
-//                         eprintln!(
-//                             "Transferred {} bytes from upstream server to proxy client {}",
-//                             count, client_addr
-//                         );
-//                     }
-//                 }
-//                 Err(err) => {
-//                     eprintln!(
-//                         "Error writing from upstream server to proxy client {}!",
-//                         client_addr
-//                     );
-//                     eprintln!("{}", err);
-//                 }
-//             };
-
-//             ()
-//         });
-//     }
-// }
\ No newline at end of file
+use async_trait::async_trait;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::config::{SharedConfig, TcpParsedRecord};
+use crate::proxy_protocol;
+use crate::metrics::{TCP_BYTES, UPSTREAM_CONNECT_ERRORS};
+
+/// Upper bound for bytes buffered while waiting for a complete ClientHello.
+const MAX_HELLO_SIZE: usize = 16 * 1024;
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Blackholed upstreams would otherwise hold the client until the OS gives up on SYN.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TcpGateway {
+    pub port: u16,
+    pub config: SharedConfig,
+}
+
+impl TcpGateway {
+    /// SNI is only needed when the port has some domain besides the fallback.
+    /// Server-first protocols (smtp, mysql, ...) never send anything before
+    /// the greeting, so peeking on fallback-only ports would hang them.
+    pub fn needs_sni(&self) -> bool {
+        self.config.load().tcp.get(&self.port).is_some_and(|records| records.keys().any(|d| d != "*"))
+    }
+
+    /// Whether every record of the port rejects the client, so nothing has to be read from it.
+    pub fn denies(&self, client_ip: Option<IpAddr>) -> bool {
+        let config = self.config.load();
+        config.tcp.get(&self.port).is_some_and(|records| {
+            records.values().all(|r| r.ip_filter.as_ref().is_some_and(|f| f.denied_by(client_ip).is_some()))
+        })
+    }
+
+    pub fn select_record(&self, sni: Option<&str>) -> Option<TcpParsedRecord> {
+        let config = self.config.load();
+        let records = config.tcp.get(&self.port)?;
+        if let Some(record) = sni.and_then(|sni| records.get(&sni.to_ascii_lowercase())) {
+            return Some(record.clone());
+        }
+        if let Some(record) = records.get("*") {
+            return Some(record.clone());
+        }
+        if sni.is_none() && records.len() == 1 {
+            return records.values().next().cloned();
+        }
+        None
+    }
+}
+
+/// Parts of TLS ClientHello used for routing.
+#[derive(Default, Debug)]
+pub struct HelloInfo {
+    pub sni: Option<String>,
+    pub alpn: Vec<Vec<u8>>,
+}
+
+/// Reads from the stream until a full TLS ClientHello is buffered and returns
+/// the buffered bytes together with the requested server name and protocols.
+/// Non-TLS traffic yields empty info, the bytes are kept anyway.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(Vec<u8>, HelloInfo)> {
+    let mut acceptor = rustls::server::Acceptor::default();
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok((buf, HelloInfo::default()));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if acceptor.read_tls(&mut &chunk[..n]).is_err() {
+            return Ok((buf, HelloInfo::default()));
+        }
+        match acceptor.accept() {
+            Ok(Some(accepted)) => {
+                let hello = accepted.client_hello();
+                let info = HelloInfo {
+                    sni: hello.server_name().map(|s| s.to_string()),
+                    alpn: hello.alpn().map(|p| p.map(|p| p.to_vec()).collect()).unwrap_or_default(),
+                };
+                return Ok((buf, info));
+            }
+            Ok(None) if buf.len() < MAX_HELLO_SIZE => continue,
+            _ => return Ok((buf, HelloInfo::default())),
+        }
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpGateway {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut downstream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let client = downstream
+            .get_socket_digest()
+            .and_then(|d| d.peer_addr().map(|a| a.to_string()))
+            .unwrap_or_default();
+        let client_ip = downstream.get_socket_digest().and_then(|d| d.peer_addr().and_then(|a| a.as_inet()).map(|a| a.ip()));
+        if self.denies(client_ip) {
+            warn!("Client {client} denied by all records of tcp port {}, dropping", self.port);
+            return None;
+        }
+
+        let (hello, sni) = if self.needs_sni() {
+            match tokio::time::timeout(HELLO_TIMEOUT, read_client_hello(&mut downstream)).await {
+                Ok(Ok((hello, info))) => (hello, info.sni),
+                Ok(Err(e)) => {
+                    warn!("Can't read client hello from {client}: {e}");
+                    return None;
+                }
+                Err(_) => {
+                    warn!("Timeout while reading client hello from {client}");
+                    return None;
+                }
+            }
+        } else {
+            (Vec::new(), None)
+        };
+
+        let Some(record) = self.select_record(sni.as_deref()) else {
+            warn!("No record for tcp port {} and sni {:?}, dropping {client}", self.port, sni);
+            return None;
+        };
+        if let Some(rule) = record.ip_filter.as_ref().and_then(|f| f.denied_by(client_ip)) {
+            warn!("Client {client} denied by {rule} of tcp port {} and sni {:?}, dropping", self.port, sni);
+            return None;
+        }
+        let Some(backend) = record.upstreams.select(&[]) else {
+            warn!("Tcp upstream {} is unhealthy, dropping {client}", record.upstream);
+            return None;
+        };
+        let Some(addr) = backend.addr.as_inet() else {
+            warn!("Tcp upstream {} is not an inet address, dropping {client}", record.upstream);
+            return None;
+        };
+        let connected = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(connected) => connected,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")),
+        };
+        let mut upstream = match connected {
+            Ok(s) => s,
+            Err(e) => {
+                UPSTREAM_CONNECT_ERRORS.with_label_values(&["tcp", &addr.to_string()]).inc();
+                warn!("Can't connect to tcp upstream {} for {client}: {e}", record.upstream);
+                return None;
+            }
+        };
+        if let Some(sni) = &sni {
+            info!("Proxying tcp {client} -> {} on port {} with sni {sni}", record.upstream, self.port);
+        } else {
+            info!("Proxying tcp {client} -> {} on port {}", record.upstream, self.port);
+        }
+
+        if let Some(version) = record.proxy_protocol {
+            let digest = downstream.get_socket_digest();
+            let inet = |a: Option<&pingora::protocols::l4::socket::SocketAddr>| a.and_then(|a| a.as_inet()).copied();
+            let source = inet(digest.as_ref().and_then(|d| d.peer_addr()));
+            let destination = inet(digest.as_ref().and_then(|d| d.local_addr()));
+            let (Some(source), Some(destination)) = (source, destination) else {
+                warn!("Can't send PROXY header to {} for non-inet client {client}", record.upstream);
+                return None;
+            };
+            if let Err(e) = upstream.write_all(&proxy_protocol::encode(version, source, destination)).await {
+                warn!("Can't send PROXY header to {}: {e}", record.upstream);
+                return None;
+            }
+        }
+
+        if let Err(e) = upstream.write_all(&hello).await {
+            warn!("Can't forward client hello to {}: {e}", record.upstream);
+            return None;
+        }
+
+        // copy_bidirectional shuts down the write half of one side as soon as
+        // the other side reaches EOF, so half-closed connections keep working
+        match tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+            Ok((sent, received)) => {
+                let sent = sent + hello.len() as u64;
+                let port = self.port.to_string();
+                TCP_BYTES.with_label_values(&[&port, &record.upstream, "sent"]).inc_by(sent);
+                TCP_BYTES.with_label_values(&[&port, &record.upstream, "received"]).inc_by(received);
+                info!("Tcp {client} <-> {} closed, {sent} bytes sent, {received} bytes received", record.upstream);
+            }
+            Err(e) => warn!("Tcp {client} <-> {} failed: {e}", record.upstream),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::ConfigRecord;
+
+    async fn gateway(source: &str) -> TcpGateway {
+        let (config, issues) = ConfigRecord::parse(source.to_string()).unwrap().to_route_config(None).await;
+        assert!(issues.is_empty(), "{issues:?}");
+        TcpGateway { port: 5432, config: Arc::new(ArcSwap::from_pointee(config)) }
+    }
+
+    fn ip(v: &str) -> Option<IpAddr> {
+        Some(v.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn client_is_denied_before_reading_only_by_all_records() {
+        let gateway = gateway(r#"
+[[tcp."5432"]]
+domain = "a.example.com"
+upstream = "127.0.0.1:5433"
+allow = ["10.0.0.0/8"]
+
+[[tcp."5432"]]
+domain = "b.example.com"
+upstream = "127.0.0.1:5434"
+deny = ["10.1.0.0/16", "192.0.2.1"]
+"#).await;
+        assert!(gateway.needs_sni());
+        assert!(gateway.denies(ip("192.0.2.1")));
+        assert!(gateway.denies(ip("::ffff:192.0.2.1")));
+        // allowed by one record, the other one may still deny it after ClientHello
+        assert!(!gateway.denies(ip("10.1.0.1")));
+        assert!(!gateway.denies(ip("192.0.2.2")));
+        assert!(!gateway.denies(None));
+    }
+
+    #[tokio::test]
+    async fn record_without_filter_accepts_everyone() {
+        let gateway = gateway(r#"
+[[tcp."5432"]]
+domain = "*"
+upstream = "127.0.0.1:5433"
+
+[[tcp."5432"]]
+domain = "a.example.com"
+upstream = "127.0.0.1:5434"
+deny = ["0.0.0.0/0"]
+"#).await;
+        assert!(!gateway.denies(ip("192.0.2.1")));
+        assert!(!gateway.denies(None));
+        assert_eq!(gateway.select_record(Some("A.example.com")).unwrap().upstream, "127.0.0.1:5434");
+        assert_eq!(gateway.select_record(Some("b.example.com")).unwrap().upstream, "127.0.0.1:5433");
+    }
+}
@@ -0,0 +1,46 @@
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::SharedConfig;
+
+/// Runs active health checks and re-resolves hostname upstreams (and auth services)
+/// of all records in current config. Each record is updated on its own schedule, see
+/// [`crate::balancer::Balancer::health_check_if_due`] and [`crate::balancer::Balancer::resolve_if_due`].
+pub struct UpstreamUpdater {
+    pub config: SharedConfig,
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamUpdater {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        return;
+                    }
+                    continue;
+                }
+                _ = tick.tick() => {}
+            }
+            let config = self.config.load();
+            let http = config.http.values().flatten().map(|r| &r.upstreams);
+            let forward_auth = config.http.values().flatten().filter_map(|r| r.forward_auth.as_ref()).map(|f| &f.upstreams);
+            let tcp = config.tcp.values().flat_map(|r| r.values()).map(|r| &r.upstreams);
+            for balancer in http.chain(forward_auth).chain(tcp) {
+                if balancer.has_dns_refresh() {
+                    let balancer = Arc::clone(balancer);
+                    tokio::spawn(async move { balancer.resolve_if_due().await });
+                }
+                if balancer.has_health_check() {
+                    let balancer = Arc::clone(balancer);
+                    tokio::spawn(async move { balancer.health_check_if_due().await });
+                }
+            }
+        }
+    }
+}
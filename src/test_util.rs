@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Http server answering every request with `response()`, returns its address
+/// and the lowercased head of the last request.
+pub async fn stub<F>(response: F) -> (SocketAddr, Arc<Mutex<String>>)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let request = Arc::new(Mutex::new(String::new()));
+    let seen = Arc::clone(&request);
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else { return };
+            let seen = Arc::clone(&seen);
+            let response = response();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                *seen.lock().unwrap() = String::from_utf8_lossy(&head).to_lowercase();
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    (addr, request)
+}
@@ -1,8 +1,11 @@
 #![allow(non_snake_case)]
 #![allow(unused)]
 
+use std::net::IpAddr;
 use std::str::FromStr;
 
+use ipnet::IpNet;
+
 #[derive(Debug)]
 pub enum ParseError {
     Missing,
@@ -53,6 +56,39 @@ impl<T> Operator<T, ParseError> for (T,) {
 }
 
 
+/// Comma separated networks from env, plain ips are single host networks.
+#[derive(Default, Debug, Clone)]
+pub struct IpNets(pub Vec<IpNet>);
+
+impl IpNets {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.find(ip).is_some()
+    }
+
+    /// First network containing `ip`.
+    pub fn find(&self, ip: &IpAddr) -> Option<&IpNet> {
+        self.0.iter().find(|net| net.contains(ip))
+    }
+
+    pub fn parse_net(v: &str) -> Result<IpNet, ipnet::AddrParseError> {
+        v.parse::<IpNet>().or_else(|e| v.parse::<IpAddr>().map(IpNet::from).map_err(|_| e))
+    }
+}
+
+impl FromStr for IpNets {
+    type Err = ipnet::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nets = s
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(Self::parse_net)
+            .collect::<Result<_, _>>()?;
+        Ok(Self(nets))
+    }
+}
+
 
 #[macro_export]
 macro_rules! env_config {